@@ -0,0 +1,107 @@
+/// Computes the edit (Levenshtein) distance between two sequences.
+///
+/// This uses the bit-parallel algorithm of Myers (1999), extended to patterns longer than 64
+/// bases with the block-based method of Hyyrö (2003). Each column of the dynamic programming
+/// matrix is computed 64 cells at a time, so this is much faster than the naive algorithm for
+/// long reads.
+pub fn edit_distance(a: &[u8], b: &[u8]) -> usize {
+    if a.is_empty() {
+        return b.len();
+    }
+
+    let mut distance = a.len();
+    myers(a, b, false, |_, score| distance = score);
+    distance
+}
+
+/// Finds the best approximate match of `pattern` within `text`, where the match may start and end
+/// anywhere in the text.
+///
+/// # Returns
+///
+/// A tuple of the edit distance of the best match, and the (exclusive) end position of the match
+/// within `text`. If several matches are equally good, the leftmost is returned.
+pub fn find_pattern(pattern: &[u8], text: &[u8]) -> (usize, usize) {
+    let mut best = (pattern.len(), 0);
+    if pattern.is_empty() {
+        return best;
+    }
+
+    myers(pattern, text, true, |col, score| {
+        if score < best.0 {
+            best = (score, col + 1);
+        }
+    });
+    best
+}
+
+/// Runs the bit-parallel edit distance algorithm of Myers, calling `f(col, score)` with the score
+/// of the last row of the dynamic programming matrix after each column of `text`.
+///
+/// If `free_start` is true, the alignment may start at any position of the text without
+/// penalty (a semi-global alignment). Otherwise the whole of both sequences is aligned.
+fn myers(pattern: &[u8], text: &[u8], free_start: bool, mut f: impl FnMut(usize, usize)) {
+    let m = pattern.len();
+    let blocks = m.div_ceil(64);
+
+    // peq[c * blocks + k] has bit i set if pattern[64k + i] == c
+    let mut peq = vec![0u64; 256 * blocks];
+    for (i, &c) in pattern.iter().enumerate() {
+        peq[c as usize * blocks + i / 64] |= 1 << (i % 64);
+    }
+
+    // the vertical deltas of each block, which all start at +1
+    let mut pv = vec![!0u64; blocks];
+    let mut mv = vec![0u64; blocks];
+
+    // the score is tracked on the last row of the pattern, which may not be the high bit of the
+    // last block. rows below it are padding, and do not affect the rows above
+    let last_bit = 1u64 << ((m - 1) % 64);
+    let mut score = m as isize;
+
+    for (col, &c) in text.iter().enumerate() {
+        let eq_row = &peq[c as usize * blocks..(c as usize + 1) * blocks];
+
+        // the horizontal delta entering the top row: +1 for a global alignment, as every base of
+        // the text must be aligned, or 0 if the alignment can start anywhere
+        let mut hin = if free_start { 0 } else { 1i32 };
+
+        for k in 0..blocks {
+            let (p, mm) = (pv[k], mv[k]);
+            let mut eq = eq_row[k];
+
+            let xv = eq | mm;
+            if hin < 0 {
+                eq |= 1;
+            }
+            let xh = ((eq & p).wrapping_add(p) ^ p) | eq;
+
+            let mut ph = mm | !(xh | p);
+            let mut mh = p & xh;
+
+            let high = if k == blocks - 1 { last_bit } else { 1 << 63 };
+            let hout = if ph & high != 0 {
+                1
+            } else if mh & high != 0 {
+                -1
+            } else {
+                0
+            };
+
+            ph <<= 1;
+            mh <<= 1;
+            if hin < 0 {
+                mh |= 1;
+            } else if hin > 0 {
+                ph |= 1;
+            }
+
+            pv[k] = mh | !(xv | ph);
+            mv[k] = ph & xv;
+            hin = hout;
+        }
+
+        score += hin as isize;
+        f(col, score as usize);
+    }
+}
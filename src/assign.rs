@@ -0,0 +1,85 @@
+use std::io::Write;
+
+use anyhow::{ensure, Context, Result};
+use csv::WriterBuilder;
+use serde::Serialize;
+
+use crate::index::{IndexReader, IndexRecord};
+use crate::select::GroupSelection;
+
+/// A row of the read-to-group assignment table.
+///
+/// # Fields
+///
+/// * `read` - The name of the read in the input file
+/// * `barcode` - The cell barcode of the read
+/// * `umi` - The UMI of the read
+/// * `group` - The index of the group which the read belongs to, as in the `UG:i` tag
+/// * `group_size` - The number of reads in the group
+#[derive(Serialize)]
+struct Assignment<'a> {
+    read: &'a str,
+    barcode: &'a str,
+    umi: &'a str,
+    group: usize,
+    group_size: usize,
+}
+
+/// Writes a tab-separated table assigning each read to its group, in input order, using only the
+/// index. Groups are numbered as in the output of `group` with the same selection. Reads which
+/// were filtered out during indexing, or whose group was not selected, are left out.
+///
+/// # Arguments
+///
+/// * `index` - The index of the input file.
+/// * `writer` - Where the table is written.
+/// * `selection` - The groups to include.
+///
+/// # Errors
+///
+/// This function will return an error if the index cannot be read, or if it was created before
+/// read names were recorded in the index.
+pub fn write_assignments(
+    index: &mut IndexReader,
+    writer: impl Write,
+    selection: &GroupSelection,
+) -> Result<()> {
+    ensure!(
+        index.has_read_names()?,
+        "The index does not record read names; please regenerate it with `nailpolish index`"
+    );
+
+    let (duplicates, _) = index.get_duplicates()?;
+    duplicates.log_selected(selection);
+
+    let mut wtr = WriterBuilder::new().delimiter(b'\t').from_writer(writer);
+
+    for read in index.index_records()? {
+        let record: IndexRecord = read?;
+        if record.ignored {
+            continue;
+        }
+        let Some((group, id, positions)) = duplicates
+            .pos_to_id
+            .get(&record.pos)
+            .and_then(|id| duplicates.by_id.get_full(id))
+        else {
+            continue;
+        };
+        if !selection.selects(id, positions.len()) {
+            continue;
+        }
+
+        wtr.serialize(Assignment {
+            read: &record.name,
+            barcode: &id.head,
+            umi: &id.tail,
+            group,
+            group_size: positions.len(),
+        })
+        .context("Could not write read assignment")?;
+    }
+    wtr.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,230 @@
+use std::io::{Result, Write};
+
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+use rayon::prelude::*;
+
+/// The maximum number of uncompressed bytes in each BGZF block. This is slightly less than
+/// 64 KiB, so that even incompressible data fits in a block once compressed, as in htslib.
+const BGZF_BLOCK_SIZE: usize = 0xff00;
+
+/// The number of uncompressed bytes in each gzip member. Gzip members have no size limit, so
+/// larger blocks are used for a better compression ratio.
+const GZIP_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// The empty block which marks the end of a BGZF file.
+const EOF_BLOCK: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// A writer which compresses its output as a series of independently compressed gzip members,
+/// either in the blocked gzip (BGZF) format used by BAM files, or as plain multi-member gzip.
+/// Both are valid gzip files.
+///
+/// Blocks are compressed in parallel on the rayon thread pool, with one batch of blocks per
+/// thread buffered before compression.
+///
+/// Any remaining data, and the end-of-file marker of a BGZF file, are only written by `finish`.
+/// A writer which is dropped without being finished, for example after an error, leaves its
+/// output without the end-of-file marker, so that it cannot be mistaken for a complete file.
+pub struct BgzfWriter<W: Write> {
+    inner: Option<W>,
+    buf: Vec<u8>,
+    block_size: usize,
+    bgzf: bool,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    /// Creates a writer which writes BGZF.
+    pub fn new(inner: W) -> Self {
+        Self::with_format(inner, BGZF_BLOCK_SIZE, true)
+    }
+
+    /// Creates a writer which writes multi-member gzip.
+    pub fn gzip(inner: W) -> Self {
+        Self::with_format(inner, GZIP_BLOCK_SIZE, false)
+    }
+
+    fn with_format(inner: W, block_size: usize, bgzf: bool) -> Self {
+        BgzfWriter {
+            inner: Some(inner),
+            // the thread pool is not queried here, as that would initialise the global pool
+            // before it can be configured
+            buf: Vec::new(),
+            block_size,
+            bgzf,
+        }
+    }
+
+    /// Compresses and writes the buffered data. Unless `all` is set, any data after the last
+    /// full block is left in the buffer.
+    fn write_blocks(&mut self, all: bool) -> Result<()> {
+        let len = if all {
+            self.buf.len()
+        } else {
+            self.buf.len() - self.buf.len() % self.block_size
+        };
+
+        let bgzf = self.bgzf;
+        let blocks: Vec<Vec<u8>> = self.buf[..len]
+            .par_chunks(self.block_size)
+            .map(|data| compress_block(data, bgzf))
+            .collect::<Result<_>>()?;
+
+        let inner = self.inner.as_mut().expect("Writer should not be finished");
+        for block in blocks {
+            inner.write_all(&block)?;
+        }
+
+        self.buf.drain(..len);
+        Ok(())
+    }
+
+    /// Writes any remaining data and the end-of-file marker, returning the underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        if !self.buf.is_empty() {
+            self.write_blocks(true)?;
+        }
+
+        let mut inner = self.inner.take().expect("Writer should not be finished");
+        if self.bgzf {
+            inner.write_all(&EOF_BLOCK)?;
+        }
+        inner.flush()?;
+        Ok(inner)
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, data: &[u8]) -> Result<usize> {
+        self.buf.extend_from_slice(data);
+
+        // compress once there is a full block for every thread
+        if self.buf.len() >= self.block_size * rayon::current_num_threads() {
+            self.write_blocks(false)?;
+        }
+        Ok(data.len())
+    }
+
+    /// Flushes the underlying writer. Buffered data is not written as a partial block, as this
+    /// would make the output less compressed.
+    fn flush(&mut self) -> Result<()> {
+        match self.inner.as_mut() {
+            Some(inner) => inner.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Drop for BgzfWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() && !std::thread::panicking() {
+            warn!("Compressed output was not finished, so is incomplete");
+        }
+    }
+}
+
+/// Compresses `data` as a single gzip member, including its header and footer. BGZF blocks
+/// also record their total size in a `BC` extra field.
+fn compress_block(data: &[u8], bgzf: bool) -> Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    let compressed = encoder.finish()?;
+
+    let mut crc = Crc::new();
+    crc.update(data);
+
+    let mut block = Vec::with_capacity(compressed.len() + 26);
+    if bgzf {
+        // the header is 18 bytes, and the footer 8
+        let block_size = compressed.len() + 26;
+
+        // gzip header, with the FEXTRA flag set
+        block.extend_from_slice(&[0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff]);
+        // the extra field: a single `BC` subfield holding the total block size minus 1
+        block.extend_from_slice(&6u16.to_le_bytes());
+        block.extend_from_slice(b"BC");
+        block.extend_from_slice(&2u16.to_le_bytes());
+        block.extend_from_slice(&((block_size - 1) as u16).to_le_bytes());
+    } else {
+        block.extend_from_slice(&[0x1f, 0x8b, 0x08, 0, 0, 0, 0, 0, 0, 0xff]);
+    }
+
+    block.extend_from_slice(&compressed);
+    block.extend_from_slice(&crc.sum().to_le_bytes());
+    block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+
+    Ok(block)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::MultiGzDecoder;
+
+    use super::*;
+
+    /// Compresses `data` in several writes, and decompresses it again with a standard gzip
+    /// decoder.
+    ///
+    /// # Returns
+    ///
+    /// The compressed and decompressed data.
+    fn round_trip(data: &[u8], writer: BgzfWriter<Vec<u8>>) -> (Vec<u8>, Vec<u8>) {
+        let mut writer = writer;
+        for chunk in data.chunks(1000) {
+            writer.write_all(chunk).unwrap();
+        }
+        let compressed = writer.finish().unwrap();
+
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(&compressed[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        (compressed, decompressed)
+    }
+
+    /// Several blocks of data, with a partial block at the end.
+    fn data() -> Vec<u8> {
+        (0..3 * BGZF_BLOCK_SIZE + 123)
+            .map(|i| b"ACGT\n"[(i * 7 + i / 3) % 5])
+            .collect()
+    }
+
+    #[test]
+    fn bgzf_round_trip() {
+        let data = data();
+        let (compressed, decompressed) = round_trip(&data, BgzfWriter::new(Vec::new()));
+
+        assert_eq!(decompressed, data);
+        assert!(compressed.ends_with(&EOF_BLOCK));
+
+        // the BC field of the first block gives its size, so the next block starts after it
+        let block_size = u16::from_le_bytes([compressed[16], compressed[17]]) as usize + 1;
+        assert_eq!(
+            &compressed[block_size..block_size + 4],
+            &[0x1f, 0x8b, 0x08, 0x04]
+        );
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        let data = data();
+        let (compressed, decompressed) = round_trip(&data, BgzfWriter::gzip(Vec::new()));
+
+        assert_eq!(decompressed, data);
+        assert!(!compressed.ends_with(&EOF_BLOCK));
+    }
+
+    #[test]
+    fn unfinished_output_has_no_eof_marker() {
+        let mut compressed = Vec::new();
+        {
+            let mut writer = BgzfWriter::new(&mut compressed);
+            writer.write_all(b"ACGT").unwrap();
+        }
+        assert!(!compressed.ends_with(&EOF_BLOCK));
+    }
+}
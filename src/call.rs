@@ -1,14 +1,221 @@
+use crate::align::edit_distance;
+use crate::checkpoint::{Checkpoint, Checkpointer};
 use crate::duplicates::DuplicateMap;
-use crate::io::{ReadType, Record, UMIGroup, UMIGroupCollection};
+use crate::io::{IgnoredOutput, ReadType, Record, UMIGroup, UMIGroupCollection};
+use crate::msa::{MsaOpts, MsaSelector};
+use crate::orient::{self, orient_single, OrientMethod, OrientOpts, Strand};
+use crate::output::OutputWriter;
+use crate::qc::{alignment_stats, qc_writer, ConsensusQc};
+use crate::trim::{trim_region, TrimOpts};
 
 use spoa::{AlignmentEngine, AlignmentType};
 
 use rayon::prelude::*;
 
+use std::borrow::Cow;
 use std::io::prelude::*;
 
 use crate::index::IndexReader;
-use anyhow::Result;
+use anyhow::{ensure, Context, Result};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::Serialize;
+use serde_json::json;
+
+/// Strategies for choosing which reads to keep when downsampling a large group.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DownsampleStrategy {
+    /// keep the reads with the highest average quality
+    Quality,
+
+    /// keep the longest reads
+    Length,
+
+    /// keep a random selection of reads
+    Random,
+}
+
+/// How a single read is produced for each group of duplicates.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CallMode {
+    /// call a consensus read from the partial order alignment of every read in the group
+    Consensus,
+
+    /// choose one of the original reads in the group, keeping its base qualities
+    Representative,
+}
+
+/// Criteria for choosing the representative read of a group.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RepresentativeStrategy {
+    /// the read with the highest average quality
+    Quality,
+
+    /// the longest read
+    Length,
+
+    /// the read with the smallest total edit distance to every other read. in groups of more
+    /// than 32 reads, distances are only measured to a random sample of 32 reads
+    Medoid,
+}
+
+/// The type of alignment used to add each read to the partial order alignment graph.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlignmentMode {
+    /// local (Smith-Waterman) alignment
+    Local,
+
+    /// global (Needleman-Wunsch) alignment
+    Global,
+
+    /// semi-global alignment, which does not penalise overhanging ends
+    Overlap,
+}
+
+/// Preset alignment parameters, tuned for the error profile of each sequencing technology.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PoaProfile {
+    /// Oxford Nanopore R9.4.1 reads, using linear gaps as in racon
+    OntR9,
+
+    /// Oxford Nanopore R10.4.1 reads, using the spoa defaults
+    OntR10,
+
+    /// PacBio HiFi reads, which rarely contain mismatches or gaps
+    Hifi,
+}
+
+/// Scoring parameters for the `spoa` alignment engine. Gaps of length `i` are scored as
+/// `min(gap_open + (i - 1) * gap_extend, gap_open_2 + (i - 1) * gap_extend_2)`, so that
+/// long gaps can be penalised less per base than short ones. All penalties are non-positive.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct AlignmentParams {
+    pub alignment: AlignmentMode,
+    pub match_score: i8,
+    pub mismatch_score: i8,
+    pub gap_open: i8,
+    pub gap_extend: i8,
+    pub gap_open_2: i8,
+    pub gap_extend_2: i8,
+}
+
+impl PoaProfile {
+    /// Returns the alignment parameters of this profile.
+    pub fn params(&self) -> AlignmentParams {
+        let (match_score, mismatch_score, gap_open, gap_extend, gap_open_2, gap_extend_2) =
+            match self {
+                PoaProfile::OntR9 => (3, -5, -4, -4, -4, -4),
+                PoaProfile::OntR10 => (5, -4, -8, -6, -10, -4),
+                PoaProfile::Hifi => (5, -10, -12, -4, -24, -2),
+            };
+
+        AlignmentParams {
+            alignment: AlignmentMode::Overlap,
+            match_score,
+            mismatch_score,
+            gap_open,
+            gap_extend,
+            gap_open_2,
+            gap_extend_2,
+        }
+    }
+}
+
+impl AlignmentParams {
+    /// Checks that the scores are of the sign expected by `spoa`.
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.match_score >= 0,
+            "The match score must be non-negative"
+        );
+        ensure!(
+            [
+                self.mismatch_score,
+                self.gap_open,
+                self.gap_extend,
+                self.gap_open_2,
+                self.gap_extend_2
+            ]
+            .iter()
+            .all(|&s| s <= 0),
+            "The mismatch and gap scores must be non-positive"
+        );
+        Ok(())
+    }
+
+    /// Creates a new `spoa` alignment engine with these parameters.
+    fn engine(&self) -> AlignmentEngine {
+        let alignment_type = match self.alignment {
+            AlignmentMode::Local => AlignmentType::kSW,
+            AlignmentMode::Global => AlignmentType::kNW,
+            AlignmentMode::Overlap => AlignmentType::kOV,
+        };
+
+        AlignmentEngine::new(
+            alignment_type,
+            self.match_score,
+            self.mismatch_score,
+            self.gap_open,
+            self.gap_extend,
+            self.gap_open_2,
+            self.gap_extend_2,
+        )
+    }
+}
+
+/// Options which control how the consensus of each group is called.
+///
+/// # Fields
+///
+/// * `max_group_size` - If set, groups with more reads than this are downsampled to this many reads
+///   before calling
+/// * `downsample_by` - The strategy used to choose which reads to keep when downsampling
+/// * `seed` - The seed used for random downsampling
+/// * `alignment` - The scoring parameters used to build the partial order alignment graph
+/// * `mode` - Whether to call a consensus or choose a representative read for each group
+/// * `representative_by` - The criterion used to choose a representative read
+/// * `orient` - How reads are oriented to a common strand before calling
+/// * `trim` - How reads are trimmed before calling
+/// * `qc` - If set, the path of a tab-separated QC report with one row per group
+/// * `msa` - If set, which groups have their multiple sequence alignment written
+#[derive(Serialize)]
+pub struct ConsensusOpts {
+    pub orient: OrientOpts,
+    pub mode: CallMode,
+    pub representative_by: RepresentativeStrategy,
+    pub max_group_size: Option<usize>,
+    pub downsample_by: DownsampleStrategy,
+    pub seed: u64,
+    pub alignment: AlignmentParams,
+    pub trim: TrimOpts,
+    pub qc: Option<String>,
+    pub msa: Option<MsaOpts>,
+}
+
+/// Writes the options used for a `call` run to a JSON file, so that the run can be reproduced.
+///
+/// # Arguments
+///
+/// * `path` - The path of the JSON file to write.
+/// * `opts` - The consensus options used for the run.
+pub fn write_params(path: &str, opts: &ConsensusOpts) -> Result<()> {
+    let params = json!({
+        "nailpolish_version": crate::cli::VERSION,
+        "command": std::env::args().collect::<Vec<_>>(),
+        "consensus": opts,
+    });
+
+    let file = std::fs::File::create(path)
+        .with_context(|| format!("Could not create parameter file {path}"))?;
+    serde_json::to_writer_pretty(file, &params)?;
+
+    Ok(())
+}
 
 enum GroupType {
     Simplex(usize),
@@ -20,12 +227,15 @@ enum GroupType {
 /// # Arguments
 ///
 /// * `input` - A string slice that holds the path to the input file.
-/// * `writer` - A mutable reference to an object that implements the `Write` trait,
-///   used for writing the output.
+/// * `writer` - The `OutputWriter` used to write the output.
 /// * `duplicates` - A `DuplicateMap` containing the duplicate reads.
-/// * `threads` - The number of threads to use for parallel processing.
 /// * `duplicates_only` - A boolean indicating whether to process only duplicate reads.
 /// * `output_originals` - A boolean indicating whether to include the original reads in the output.
+/// * `opts` - Options which control how the consensus of each group is called.
+/// * `ignored` - Where reads which were filtered out during indexing are written, if at all.
+/// * `checkpointer` - If set, progress is checkpointed after each chunk of groups is written, and
+///   the run continues from its last checkpoint. The writer must already be positioned at the
+///   offset of that checkpoint.
 ///
 /// # Returns
 ///
@@ -33,38 +243,61 @@ enum GroupType {
 ///   during processing.
 pub fn consensus(
     collection: &mut UMIGroupCollection,
-    writer: &mut impl Write,
-    threads: usize,
+    writer: &mut OutputWriter<impl Write>,
     duplicates_only: bool,
     output_originals: bool,
+    opts: &ConsensusOpts,
+    ignored: &mut IgnoredOutput,
+    checkpointer: Option<&Checkpointer>,
 ) -> Result<()> {
-    info!("Creating thread pool with {threads} threads");
+    if let Some(max_group_size) = opts.max_group_size {
+        ensure!(max_group_size > 0, "--max-group-size must be at least 1");
+        info!(
+            "Downsampling groups larger than {max_group_size} reads by {:?}",
+            opts.downsample_by
+        );
+    }
 
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(threads)
-        .build_global()?;
+    let mut qc_writer = opts.qc.as_deref().map(qc_writer).transpose()?;
+    let mut msa_selector = opts.msa.as_ref().map(MsaSelector::new).transpose()?;
 
-    let mut duplicate_iterator = collection.stream_iter(duplicates_only);
+    // the global thread pool must already be built, with the number of threads requested
+    let chunk_size = 100usize * rayon::current_num_threads();
 
-    let chunk_size = 100usize * threads;
+    // the records of each chunk are read at once
+    let mut duplicate_iterator = collection.stream_iter(duplicates_only, ignored.include());
+    duplicate_iterator.batch(chunk_size);
+
+    // groups which were written before the last checkpoint are skipped
+    let resume_from = checkpointer.map(|c| c.resume_from()).unwrap_or_default();
+    duplicate_iterator.skip_groups(resume_from.groups);
 
     // this vector stores the indexes of each group within the buf_duplicates and buf_single buffers
     let mut buf_locations = Vec::with_capacity(chunk_size);
     let mut buf_duplicates = Vec::new();
     let mut buf_single = Vec::new();
 
-    let mut idx = 0;
-    let mut first = true;
+    let mut idx = resume_from.groups;
+
+    // the number of groups which were downsampled, and the number of reads dropped from them
+    let mut downsampled_groups = 0usize;
+    let mut dropped_reads = 0usize;
 
     let mut end_of_buffer = false;
     loop {
-        if let Some(group) = duplicate_iterator.next()? {
+        if let Some(mut group) = duplicate_iterator.next()? {
             idx += 1;
 
-            if (idx > 0) && (idx % 100000 == 0) {
+            if (idx > 0) && idx.is_multiple_of(100000) {
                 info!("Called {} reads...", idx);
             }
 
+            if let Some(selector) = msa_selector.as_mut() {
+                if !group.ignore && selector.select(&group) {
+                    group.msa = Some(Vec::new());
+                }
+            }
+
             let single = group.records.len() == 1;
             if (single && !duplicates_only) || group.ignore {
                 buf_locations.push(GroupType::Simplex(buf_single.len()));
@@ -83,8 +316,16 @@ pub fn consensus(
         if (buf_locations.len() == chunk_size) || end_of_buffer {
             // single records are not multithreaded to save on IPC costs;
             // use rayon to multithread duplicate buffer record calling
-            buf_single.iter_mut().for_each(call_umi_group);
-            buf_duplicates.par_iter_mut().for_each(call_umi_group);
+            buf_single.iter_mut().for_each(|g| {
+                call_umi_group(g, opts);
+            });
+            let dropped: Vec<usize> = buf_duplicates
+                .par_iter_mut()
+                .map(|g| call_umi_group(g, opts))
+                .collect();
+
+            downsampled_groups += dropped.iter().filter(|&&d| d > 0).count();
+            dropped_reads += dropped.iter().sum::<usize>();
 
             for (pos, loc) in buf_locations.iter().enumerate() {
                 let group = match loc {
@@ -95,11 +336,6 @@ pub fn consensus(
 
                 // output original reads as well, if requested
                 if matches!(loc, GroupType::Duplex(_)) && output_originals {
-                    if !first {
-                        writer.write_all(b"\n")?;
-                    }
-                    first = false;
-
                     let group_size = group.records.len();
                     for (idx, r) in group.records.iter_mut().enumerate() {
                         r.add_metadata(
@@ -109,18 +345,44 @@ pub fn consensus(
                             group_size,
                             group.avg_qual,
                         );
-                        r.write_fastq(&mut *writer)?;
+                        if let Some(Some(strand)) = group.strands.get(idx) {
+                            r.add_tag("ST:A", strand);
+                        }
+                        if let Some(region) = group.trims.get(idx) {
+                            r.add_tag("TR:Z", region);
+                            if opts.trim.trim_originals {
+                                *r = r.trimmed(region);
+                            }
+                        }
+                        writer.write(r, &group.id)?;
                     }
                 }
 
-                // add a newline at the start, unless this is the first line in the file
-                if !first {
-                    writer.write_all(b"\n")?;
+                if let (Some(wtr), Some(qc)) = (qc_writer.as_mut(), group.qc.as_ref()) {
+                    wtr.serialize(qc)?;
+                }
+                if let Some(selector) = msa_selector.as_mut() {
+                    selector.record(group)?;
                 }
-                first = false;
 
-                let rec = group.consensus.as_mut().expect("Should never be None");
-                rec.write_fastq(&mut *writer)?;
+                let rec = group.consensus.as_ref().expect("Should never be None");
+                match ignored {
+                    IgnoredOutput::Separate(ignored_writer) if group.ignore => {
+                        ignored_writer.write(rec, &group.id)?
+                    }
+                    _ => writer.write(rec, &group.id)?,
+                }
+            }
+
+            // once the whole chunk is written, it can be checkpointed
+            if let Some(checkpointer) = checkpointer {
+                if !buf_locations.is_empty() {
+                    writer.flush()?;
+                    checkpointer.save(Checkpoint {
+                        groups: idx,
+                        offset: writer.position(),
+                    })?;
+                }
             }
 
             // empty the buffer
@@ -135,48 +397,250 @@ pub fn consensus(
         }
     }
 
+    if let Some(mut wtr) = qc_writer {
+        wtr.flush()?;
+    }
+    if let Some(selector) = msa_selector {
+        selector.finish()?;
+    }
+
+    if opts.max_group_size.is_some() {
+        info!("Downsampled {downsampled_groups} groups, dropping {dropped_reads} reads from consensus calling");
+    }
+
     Ok(())
 }
 
+/// Chooses which reads of a group to use for consensus calling, if the group is larger than
+/// `opts.max_group_size`.
+///
+/// # Returns
+///
+/// The indices of the chosen reads, in their original order, or `None` if every read should be used.
+fn downsample(group: &UMIGroup, opts: &ConsensusOpts) -> Option<Vec<usize>> {
+    let max_group_size = opts.max_group_size?;
+    let length = group.records.len();
+    if length <= max_group_size {
+        return None;
+    }
+
+    let mut chosen: Vec<usize> = match opts.downsample_by {
+        DownsampleStrategy::Quality => {
+            let quals: Vec<f64> = group
+                .records
+                .iter()
+                .map(|r| r.phred_quality_avg())
+                .collect();
+            let mut indices: Vec<usize> = (0..length).collect();
+            indices.sort_by(|&a, &b| quals[b].total_cmp(&quals[a]));
+            indices.truncate(max_group_size);
+            indices
+        }
+        DownsampleStrategy::Length => {
+            let mut indices: Vec<usize> = (0..length).collect();
+            indices.sort_by_key(|&i| std::cmp::Reverse(group.records[i].len()));
+            indices.truncate(max_group_size);
+            indices
+        }
+        DownsampleStrategy::Random => {
+            // seed by the group index, so that the result does not depend on thread scheduling
+            let mut rng = StdRng::seed_from_u64(opts.seed.wrapping_add(group.index as u64));
+            rand::seq::index::sample(&mut rng, length, max_group_size).into_vec()
+        }
+    };
+
+    chosen.sort_unstable();
+    Some(chosen)
+}
+
+/// The number of reads which each read is compared to when choosing the medoid of a larger
+/// group, so that the number of alignments grows linearly rather than quadratically.
+const MEDOID_SAMPLE_SIZE: usize = 32;
+
+/// Chooses the representative read of a group.
+///
+/// # Arguments
+///
+/// * `records` - The reads of the group.
+/// * `by` - The criterion used to choose the read.
+/// * `seed` - The seed used to sample the reads which each read is compared to, for the medoid.
+///
+/// # Returns
+///
+/// The index of the chosen read within `records`. Ties are broken by choosing the earliest read.
+fn representative(records: &[Cow<Record>], by: RepresentativeStrategy, seed: u64) -> usize {
+    let scores: Vec<f64> = match by {
+        RepresentativeStrategy::Quality => records.iter().map(|r| r.phred_quality_avg()).collect(),
+        RepresentativeStrategy::Length => records.iter().map(|r| r.len() as f64).collect(),
+        RepresentativeStrategy::Medoid if records.len() > MEDOID_SAMPLE_SIZE => {
+            // compare each read to the same random sample of reads. a read in the sample is left
+            // out of its own comparisons, so the mean distance is used to score every read alike
+            let mut rng = StdRng::seed_from_u64(seed);
+            let sample = rand::seq::index::sample(&mut rng, records.len(), MEDOID_SAMPLE_SIZE);
+            records
+                .iter()
+                .enumerate()
+                .map(|(i, r)| {
+                    let (total, n) = sample.iter().filter(|&j| j != i).fold((0, 0), |(t, n), j| {
+                        let d = edit_distance(r.seq.as_bytes(), records[j].seq.as_bytes());
+                        (t + d, n + 1)
+                    });
+                    -(total as f64 / n as f64)
+                })
+                .collect()
+        }
+        RepresentativeStrategy::Medoid => {
+            // the distance matrix is symmetric, so each pair only needs to be aligned once
+            let mut total = vec![0usize; records.len()];
+            for i in 0..records.len() {
+                for j in (i + 1)..records.len() {
+                    let d = edit_distance(records[i].seq.as_bytes(), records[j].seq.as_bytes());
+                    total[i] += d;
+                    total[j] += d;
+                }
+            }
+
+            // negate, so that the smallest total distance has the highest score
+            total.iter().map(|&d| -(d as f64)).collect()
+        }
+    };
+
+    let mut best = 0;
+    for (i, &score) in scores.iter().enumerate() {
+        if score > scores[best] {
+            best = i;
+        }
+    }
+    best
+}
+
 /// Generates a consensus sequence from a group of reads.
 ///
 /// # Arguments
 ///
 /// * `group` - A `UMIGroup` containing the reads to be processed.
-/// * `output_originals` - A boolean indicating whether to include the original reads in the
-///   output alongside the consensus read.
+/// * `opts` - Options which control how the consensus is called.
 ///
 /// # Returns
 ///
-/// A `String` containing the consensus sequence in FASTQ format.
-fn call_umi_group(group: &mut UMIGroup) {
+/// The number of reads which were dropped from the group by downsampling. The consensus
+/// read is stored in `group.consensus`.
+fn call_umi_group(group: &mut UMIGroup, opts: &ConsensusOpts) -> usize {
     let length = group.records.len();
 
-    // // process ignored reads first
-    // if group.ignore {
-    //     for record in group.records.iter() {
-    //         io::write_read(&mut output, record, &group, ReadType::Ignored, false).unwrap();
-    //     }
-    //     return output.into_inner();
-    // }
+    // ignored reads are passed through unchanged
+    if group.ignore {
+        let mut rec = group.records[0].clone();
+        rec.add_metadata(group.index, ReadType::Ignored, 1, 1, group.avg_qual);
+        group.consensus = Some(rec);
+        return 0;
+    }
+
+    let orient = opts.orient.method != OrientMethod::None;
 
-    // for singletons, the read is its own consensus
+    // find the region of each read to keep, before orienting, so that the coordinates are
+    // relative to the original read
+    if opts.trim.enabled() {
+        group.trims = group
+            .records
+            .iter()
+            .map(|r| trim_region(r.seq.as_bytes(), &opts.trim))
+            .collect();
+    }
+
+    // for singletons, the read is its own consensus. like the consensus of a larger group, it is
+    // written on the common strand, so is reverse complemented if it was detected as reverse
     if length == 1 {
-        let mut rec = group.records[0].clone();
+        let mut rec = match group.trims.first() {
+            Some(region) => group.records[0].trimmed(region),
+            None => group.records[0].clone(),
+        };
+        if orient {
+            let strand = orient_single(&group.records[0], &opts.orient);
+            rec = rec.on_strand(strand).into_owned();
+        }
+        if opts.qc.is_some() {
+            group.qc = Some(ConsensusQc::new(group, &rec, (1.0, 0)));
+        }
+        if let Some(msa) = group.msa.as_mut() {
+            // a single read is aligned only to itself
+            msa.push((group.records[0].id.clone(), rec.seq.clone()));
+            msa.push((format!("{} consensus", group.id), rec.seq.clone()));
+        }
 
         rec.add_metadata(group.index, ReadType::Single, 1, 1, group.avg_qual);
+        if orient {
+            rec.add_tag("ST:A", Strand::Forward);
+        }
+        if let Some(region) = group.trims.first() {
+            rec.add_tag("TR:Z", region);
+        }
 
         group.consensus = Some(rec);
 
-        return;
+        return 0;
+    }
+
+    let chosen = downsample(group, opts);
+    let dropped = chosen.as_ref().map_or(0, |c| length - c.len());
+    let chosen = chosen.unwrap_or_else(|| (0..length).collect());
+
+    // orient each read to a common strand. this uses the untrimmed reads, as the polyA tail or
+    // primer used to detect the strand may be trimmed
+    let originals: Vec<&Record> = chosen.iter().map(|&i| &group.records[i]).collect();
+    let strands = if orient {
+        let strands = orient::orient(&originals, &opts.orient);
+
+        group.strands = vec![None; length];
+        for (&i, &strand) in chosen.iter().zip(strands.iter()) {
+            group.strands[i] = Some(strand);
+        }
+        strands
+    } else {
+        vec![Strand::Forward; chosen.len()]
+    };
+
+    // trim each read, then reverse complement it if needed
+    let records: Vec<Cow<Record>> = chosen
+        .iter()
+        .zip(originals)
+        .zip(strands)
+        .map(|((&i, r), strand)| match group.trims.get(i) {
+            Some(region) => Cow::Owned(r.trimmed(region).on_strand(strand).into_owned()),
+            None => r.on_strand(strand),
+        })
+        .collect();
+
+    // choose one of the original reads, instead of calling a consensus
+    if opts.mode == CallMode::Representative {
+        // seed by the group index, so that the result does not depend on thread scheduling
+        let seed = opts.seed.wrapping_add(group.index as u64);
+        let mut rec = records[representative(&records, opts.representative_by, seed)]
+            .clone()
+            .into_owned();
+        rec.id = group.id.to_string();
+        rec.add_metadata(
+            group.index,
+            ReadType::Consensus,
+            0,
+            group.records.len(),
+            group.avg_qual,
+        );
+
+        if orient {
+            rec.add_tag("ST:A", Strand::Forward);
+        }
+
+        group.consensus = Some(rec);
+        return dropped;
     }
 
     // initialise `spoa` machinery
-    let mut alignment_engine = AlignmentEngine::new(AlignmentType::kOV, 5, -4, -8, -6, -10, -4);
+    let mut alignment_engine = opts.alignment.engine();
     let mut poa_graph = spoa::Graph::new();
 
     // add each read in the duplicate group to the graph
-    for record in group.records.iter() {
+    for record in records.iter() {
         // TODO: align originals and output as well
 
         // Align to the graph
@@ -192,6 +656,25 @@ fn call_umi_group(group: &mut UMIGroup) {
         qual: consensus.quality,
     };
 
+    if opts.qc.is_some() || group.msa.is_some() {
+        let msa = poa_graph.multiple_sequence_alignment(true);
+        let rows: Vec<&[u8]> = msa.iter().map(|r| r.to_bytes()).collect();
+
+        if opts.qc.is_some() {
+            group.qc = Some(ConsensusQc::new(group, &rec, alignment_stats(&rows)));
+        }
+        if let Some(msa) = group.msa.as_mut() {
+            let names = records
+                .iter()
+                .map(|r| r.id.clone())
+                .chain(std::iter::once(format!("{} consensus", group.id)));
+            *msa = names
+                .zip(rows)
+                .map(|(name, row)| (name, String::from_utf8_lossy(row).into_owned()))
+                .collect();
+        }
+    }
+
     rec.add_metadata(
         group.index,
         ReadType::Consensus,
@@ -199,6 +682,11 @@ fn call_umi_group(group: &mut UMIGroup) {
         group.records.len(),
         group.avg_qual,
     );
+    if orient {
+        rec.add_tag("ST:A", Strand::Forward);
+    }
 
     group.consensus = Some(rec);
+
+    dropped
 }
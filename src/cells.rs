@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use anyhow::{Context, Result};
+use csv::WriterBuilder;
+use serde::Serialize;
+
+use crate::duplicates::DuplicateMap;
+
+/// Duplicate statistics for a single cell barcode, i.e. for all the groups which share the
+/// same `RecordIdentifier.head`.
+///
+/// # Fields
+///
+/// * `barcode` - The cell barcode
+/// * `reads` - The number of reads with this barcode
+/// * `umis` - The number of unique UMIs (groups) with this barcode
+/// * `duplicate_reads` - The number of reads which are in a group with more than one read
+/// * `proportion_duplicate` - The proportion of reads which are duplicates
+/// * `saturation` - The sequencing saturation, `1 - umis / reads`
+#[derive(Serialize, Debug)]
+pub struct CellStatistics {
+    pub barcode: String,
+    pub reads: usize,
+    pub umis: usize,
+    pub duplicate_reads: usize,
+    pub proportion_duplicate: f64,
+    pub saturation: f64,
+}
+
+impl DuplicateMap {
+    /// Computes duplicate statistics for each cell barcode.
+    ///
+    /// # Returns
+    ///
+    /// A vector of `CellStatistics`, sorted by read count in descending order.
+    pub fn cell_statistics(&self) -> Vec<CellStatistics> {
+        let mut cells: HashMap<&str, CellStatistics> = HashMap::new();
+
+        for (id, positions) in self.by_id.iter() {
+            let cell = cells
+                .entry(id.head.as_str())
+                .or_insert_with(|| CellStatistics {
+                    barcode: id.head.clone(),
+                    reads: 0,
+                    umis: 0,
+                    duplicate_reads: 0,
+                    proportion_duplicate: 0.0,
+                    saturation: 0.0,
+                });
+
+            cell.reads += positions.len();
+            cell.umis += 1;
+            if positions.len() > 1 {
+                cell.duplicate_reads += positions.len();
+            }
+        }
+
+        let mut cells: Vec<CellStatistics> = cells.into_values().collect();
+        for cell in cells.iter_mut() {
+            cell.proportion_duplicate = cell.duplicate_reads as f64 / cell.reads as f64;
+            cell.saturation = 1.0 - (cell.umis as f64 / cell.reads as f64);
+        }
+
+        // sort by read count, breaking ties by barcode so that the output is stable
+        cells.sort_by(|a, b| {
+            b.reads
+                .cmp(&a.reads)
+                .then_with(|| a.barcode.cmp(&b.barcode))
+        });
+        cells
+    }
+}
+
+/// Writes per-cell statistics to a tab-separated file, with a header row.
+pub fn write_cell_statistics(path: &str, cells: &[CellStatistics]) -> Result<()> {
+    let mut wtr = WriterBuilder::new().delimiter(b'\t').from_path(path)?;
+    for cell in cells {
+        wtr.serialize(cell)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Barcodes with fewer reads than this are not considered by the knee and inflection methods,
+/// as the tail of the barcode rank curve is dominated by noise.
+const MIN_CELL_READS: usize = 10;
+
+/// The maximum number of points of the barcode rank curve which are kept for plotting.
+const MAX_RANK_POINTS: usize = 500;
+
+/// How cells are distinguished from background barcodes.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CellCalling {
+    /// the knee of the barcode rank curve, the point furthest above the line joining its ends
+    /// on a log-log scale
+    Knee,
+
+    /// the inflection point of the barcode rank curve, where reads per barcode fall most
+    /// steeply on a log-log scale
+    Inflection,
+
+    /// a tenth of the reads of the 99th percentile of the expected number of cells, as in Cell
+    /// Ranger 2. this is used when `--expected-cells` is given
+    #[value(skip)]
+    Expected,
+}
+
+/// The barcodes which were called as cells.
+///
+/// # Fields
+///
+/// * `method` - How cells were called
+/// * `threshold` - The fewest reads of any called cell
+/// * `called_cells` - The number of barcodes called as cells
+/// * `reads_in_cells` - The number of reads of the called cells
+/// * `fraction_reads_in_cells` - `reads_in_cells` as a fraction of every grouped read, or 0 if no
+///   reads were grouped
+/// * `barcode_ranks` - Points of the barcode rank curve, as `(rank, reads)`, for plotting. Long
+///   curves are thinned to points which are evenly spaced on a log scale
+#[derive(Serialize, Debug)]
+pub struct CellCalls {
+    pub method: CellCalling,
+    pub threshold: usize,
+    pub called_cells: usize,
+    pub reads_in_cells: usize,
+    pub fraction_reads_in_cells: f64,
+    pub barcode_ranks: Vec<(usize, usize)>,
+}
+
+/// Returns the index of the knee of a descending curve, which is the point furthest above the
+/// line joining its first and last points on a log-log scale.
+fn knee(reads: &[usize]) -> usize {
+    let points: Vec<(f64, f64)> = reads
+        .iter()
+        .enumerate()
+        .map(|(i, &r)| (((i + 1) as f64).log10(), (r as f64).log10()))
+        .collect();
+    let (Some(&(x0, y0)), Some(&(x1, y1))) = (points.first(), points.last()) else {
+        return 0;
+    };
+
+    // the distance of each point above the line, up to a constant factor
+    let distance = |&(x, y): &(f64, f64)| (x1 - x0) * y - (y1 - y0) * x - x1 * y0 + y1 * x0;
+
+    points
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| distance(a).total_cmp(&distance(b)))
+        .map_or(0, |(i, _)| i)
+}
+
+/// Returns the index of the inflection point of a descending curve, which is the point before the
+/// steepest fall in reads on a log-log scale. Tied read counts are treated as a single point at
+/// their middle rank, as in DropletUtils.
+fn inflection(reads: &[usize]) -> usize {
+    // (index of the first barcode, middle rank, reads) of each run of tied read counts
+    let mut runs: Vec<(usize, f64, usize)> = Vec::new();
+    let mut start = 0;
+    for i in 1..=reads.len() {
+        if i == reads.len() || reads[i] != reads[start] {
+            let middle = (start + i + 1) as f64 / 2.0;
+            runs.push((start, middle, reads[start]));
+            start = i;
+        }
+    }
+
+    runs.windows(2)
+        .min_by(|a, b| {
+            let slope = |w: &[(usize, f64, usize)]| {
+                ((w[1].2 as f64).log10() - (w[0].2 as f64).log10())
+                    / (w[1].1.log10() - w[0].1.log10())
+            };
+            slope(a).total_cmp(&slope(b))
+        })
+        .map_or(0, |w| w[0].0)
+}
+
+/// Calls cells from the number of reads of each barcode.
+///
+/// # Arguments
+///
+/// * `cells` - The statistics of each barcode, sorted by read count in descending order, as
+///   returned by `DuplicateMap::cell_statistics`.
+/// * `method` - How cells are called, if `expected_cells` is not given.
+/// * `expected_cells` - If set, the number of cells which are expected.
+/// * `total_reads` - The number of reads of every barcode.
+pub fn call_cells(
+    cells: &[CellStatistics],
+    method: CellCalling,
+    expected_cells: Option<usize>,
+    total_reads: usize,
+) -> CellCalls {
+    let reads: Vec<usize> = cells.iter().map(|c| c.reads).collect();
+
+    let (method, threshold) = match expected_cells {
+        Some(n) => {
+            let top = &reads[..n.min(reads.len())];
+            let percentile = top.get((0.01 * top.len().saturating_sub(1) as f64).round() as usize);
+            (
+                CellCalling::Expected,
+                percentile.map_or(0, |&r| r / 10).max(1),
+            )
+        }
+        None => {
+            let considered = &reads[..reads.partition_point(|&r| r >= MIN_CELL_READS)];
+            let threshold = match method {
+                _ if considered.is_empty() => MIN_CELL_READS,
+                CellCalling::Inflection => considered[inflection(considered)],
+                _ => considered[knee(considered)],
+            };
+            (method, threshold)
+        }
+    };
+
+    let called_cells = reads.partition_point(|&r| r >= threshold);
+    let reads_in_cells: usize = reads[..called_cells].iter().sum();
+    info!(
+        "Called {called_cells} of {} barcodes as cells, with at least {threshold} reads each",
+        reads.len()
+    );
+
+    // keep points which are evenly spaced on a log scale, along with the last point
+    let step = (reads.len() as f64).ln() / MAX_RANK_POINTS as f64;
+    let mut barcode_ranks: Vec<(usize, usize)> = Vec::new();
+    for (i, &r) in reads.iter().enumerate() {
+        let rank = i + 1;
+        let keep = match barcode_ranks.last() {
+            None => true,
+            Some(&(last, _)) => rank == reads.len() || (rank as f64 / last as f64).ln() >= step,
+        };
+        if keep {
+            barcode_ranks.push((rank, r));
+        }
+    }
+
+    CellCalls {
+        method,
+        threshold,
+        called_cells,
+        reads_in_cells,
+        fraction_reads_in_cells: if total_reads == 0 {
+            0.0
+        } else {
+            reads_in_cells as f64 / total_reads as f64
+        },
+        barcode_ranks,
+    }
+}
+
+/// Writes the barcodes of the called cells to a file, one per line, in a form which can be passed
+/// to `--barcodes`.
+pub fn write_called_barcodes(
+    path: &str,
+    cells: &[CellStatistics],
+    calls: &CellCalls,
+) -> Result<()> {
+    let mut writer =
+        BufWriter::new(File::create(path).with_context(|| format!("Could not create {path}"))?);
+    for cell in &cells[..calls.called_cells] {
+        writeln!(writer, "{}", cell.barcode)?;
+    }
+    writer.flush()?;
+    info!("Wrote {} called barcodes to {path}", calls.called_cells);
+    Ok(())
+}
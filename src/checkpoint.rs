@@ -0,0 +1,189 @@
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::{ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// The progress of a `call` run, as of the last chunk of groups which was fully written.
+///
+/// # Fields
+///
+/// * `groups` - The number of groups which have been written
+/// * `offset` - The length of the output, in bytes, once those groups were written
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub groups: usize,
+    pub offset: u64,
+}
+
+/// What a `call` run was given, so that it is only resumed by a run which would write the same
+/// output.
+///
+/// # Fields
+///
+/// * `input` - The path of the input file
+/// * `input_size` - The size of the input file, in bytes
+/// * `input_modified` - When the input file was last modified
+/// * `index` - The path of the index
+/// * `opts` - The options which control how the consensus of each group is called
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Fingerprint {
+    pub input: String,
+    pub input_size: u64,
+    pub input_modified: SystemTime,
+    pub index: String,
+    pub opts: Value,
+}
+
+impl Fingerprint {
+    /// Creates the fingerprint of a run.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - The path of the input file.
+    /// * `index` - The path of the index.
+    /// * `opts` - The options of the run, such as its `ConsensusOpts`.
+    pub fn new(input: &str, index: &str, opts: &impl Serialize) -> Result<Self> {
+        let metadata = std::fs::metadata(input)
+            .with_context(|| format!("Could not read the metadata of {input}"))?;
+
+        // paths are compared in full, so that the run can be resumed from another directory
+        let absolute = |path: &str| {
+            std::fs::canonicalize(path).map_or(path.into(), |p| p.to_string_lossy().into_owned())
+        };
+
+        Ok(Fingerprint {
+            input: absolute(input),
+            input_size: metadata.len(),
+            input_modified: metadata.modified()?,
+            index: absolute(index),
+            opts: serde_json::to_value(opts)?,
+        })
+    }
+}
+
+/// The contents of a checkpoint file, which is written with a borrowed fingerprint and read with
+/// an owned one.
+#[derive(Serialize, Deserialize)]
+struct CheckpointFile<F> {
+    fingerprint: F,
+    #[serde(flatten)]
+    checkpoint: Checkpoint,
+}
+
+/// Saves the progress of a `call` run to a sidecar file next to its output, `<output>.checkpoint`,
+/// so that an interrupted run can be resumed with `--resume`. The sidecar is removed once the
+/// run finishes.
+///
+/// Each checkpoint records the `Fingerprint` of its run, and a run is only resumed from a
+/// checkpoint with the same fingerprint.
+pub struct Checkpointer {
+    output: String,
+    path: String,
+    fingerprint: Fingerprint,
+    resume_from: Checkpoint,
+}
+
+impl Checkpointer {
+    /// Creates a checkpointer for the given output file.
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - The path of the output file.
+    /// * `resume` - Whether to resume from an existing checkpoint. If there is none, the run
+    ///   starts from the beginning.
+    /// * `fingerprint` - The fingerprint of this run, which must match that of the checkpoint.
+    pub fn new(output: &str, resume: bool, fingerprint: Fingerprint) -> Result<Self> {
+        let path = format!("{output}.checkpoint");
+
+        let resume_from = if !resume {
+            Checkpoint::default()
+        } else if Path::new(&path).exists() {
+            let file =
+                File::open(&path).with_context(|| format!("Could not open checkpoint {path}"))?;
+            let saved: CheckpointFile<Fingerprint> = serde_json::from_reader(file)
+                .with_context(|| format!("Could not read checkpoint {path}"))?;
+            ensure!(
+                saved.fingerprint == fingerprint,
+                "Checkpoint {path} was written by a run with a different input, index or options, \
+                 so cannot be resumed; rerun without --resume to start again"
+            );
+
+            let checkpoint = saved.checkpoint;
+            info!(
+                "Resuming from checkpoint after {} groups ({} bytes of output)",
+                checkpoint.groups, checkpoint.offset
+            );
+            checkpoint
+        } else {
+            warn!("No checkpoint found at {path}, so starting from the beginning");
+            Checkpoint::default()
+        };
+
+        Ok(Checkpointer {
+            output: output.into(),
+            path,
+            fingerprint,
+            resume_from,
+        })
+    }
+
+    /// Returns the checkpoint which the run continues from. This is empty unless resuming.
+    pub fn resume_from(&self) -> Checkpoint {
+        self.resume_from
+    }
+
+    /// Saves a checkpoint. The output must already be flushed up to `checkpoint.offset`.
+    ///
+    /// The output is synced to disk before the checkpoint is written, so that a checkpoint never
+    /// refers to output which could be lost in a crash. The checkpoint is written to a temporary
+    /// file, synced, and then renamed, so that an interruption never leaves a partially written
+    /// checkpoint behind.
+    pub fn save(&self, checkpoint: Checkpoint) -> Result<()> {
+        // syncing any handle to a file syncs all of its written data
+        OpenOptions::new()
+            .write(true)
+            .open(&self.output)
+            .and_then(|f| f.sync_data())
+            .with_context(|| format!("Could not sync {} to disk", self.output))?;
+
+        let tmp = format!("{}.tmp", self.path);
+        let file =
+            File::create(&tmp).with_context(|| format!("Could not create checkpoint {tmp}"))?;
+        let saved = CheckpointFile {
+            fingerprint: &self.fingerprint,
+            checkpoint,
+        };
+        serde_json::to_writer(&file, &saved)?;
+        file.sync_all()
+            .with_context(|| format!("Could not sync checkpoint {tmp} to disk"))?;
+
+        std::fs::rename(&tmp, &self.path)
+            .with_context(|| format!("Could not write checkpoint {}", self.path))?;
+
+        // the rename is only durable once the directory holding the checkpoint is synced
+        #[cfg(unix)]
+        if let Some(dir) = Path::new(&self.path).parent() {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            File::open(dir)
+                .and_then(|d| d.sync_all())
+                .with_context(|| format!("Could not sync checkpoint {}", self.path))?;
+        }
+        Ok(())
+    }
+
+    /// Removes the checkpoint, once the run has finished.
+    pub fn finish(self) -> Result<()> {
+        if Path::new(&self.path).exists() {
+            std::fs::remove_file(&self.path)
+                .with_context(|| format!("Could not remove checkpoint {}", self.path))?;
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,17 @@
 use clap::builder::styling::AnsiColor;
-use clap::builder::Styles;
-use clap::{Parser, Subcommand};
+use clap::builder::{RangedU64ValueParser, Styles};
+use clap::{Args, Parser, Subcommand};
+
+use crate::call::{AlignmentMode, AlignmentParams, PoaProfile};
+use crate::cells::CellCalling;
+use crate::msa::MsaOpts;
+use crate::output::{OutputCompression, OutputFormat};
+use crate::partition::ReadStrategy;
+use crate::select::{read_barcodes, GroupSelection};
+use crate::split::SplitBy;
+use crate::summary::SummaryFormat;
+use crate::trim::{read_adapters, TrimOpts};
+use anyhow::{ensure, Result};
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 const INFO_STRING: &str = "
@@ -89,13 +100,41 @@ pub enum Commands {
     /// Generate a summary of duplicate statistics from an index file
     #[command(arg_required_else_help = true)]
     Summary {
-        /// the index file
-        #[arg(long)]
-        index: String,
+        /// the index file. give several indexes to compare their samples in one report
+        #[arg(long, required = true, num_args = 1.., verbatim_doc_comment)]
+        index: Vec<String>,
+
+        /// the name of each sample, in the same order as --index. defaults to the name of each
+        /// input file, without its extensions
+        #[arg(long, num_args = 1.., verbatim_doc_comment)]
+        sample_name: Vec<String>,
+
+        /// output file. defaults to summary.html for the html format, and to standard output
+        /// otherwise
+        #[arg(short, verbatim_doc_comment)]
+        output: Option<String>,
 
-        /// output file
-        #[arg(short, default_value = "summary.html")]
-        output: String,
+        #[command(flatten)]
+        report: ReportArgs,
+
+        #[command(flatten)]
+        cells: CellArgs,
+
+        /// the amount of additional sequencing, relative to the current depth, for which to
+        /// estimate the number of new unique molecules. `1` estimates the yield of another
+        /// lane of the same depth
+        #[arg(
+            long,
+            default_value_t = 1.0,
+            value_parser = parse_extra_depth,
+            verbatim_doc_comment
+        )]
+        extra_depth: f64,
+
+        /// also write the summary as MultiQC custom content, to <MULTIQC>.general_stats_mqc.json
+        /// and <MULTIQC>.group_sizes_mqc.json
+        #[arg(long, verbatim_doc_comment)]
+        multiqc: Option<String>,
     },
 
     /// Generate a consensus-called 'cleaned up' file
@@ -109,10 +148,23 @@ pub enum Commands {
         #[arg(long)]
         input: String,
 
-        /// the output .fastq
+        /// the output file, or default to stdout
         #[arg(short)]
         output: Option<String>,
 
+        /// the format of the output. in `sam` and `ubam` output, group metadata is written as SAM
+        /// tags, along with the barcode and UMI as `CB` and `UB` tags
+        #[arg(long, value_enum, default_value = "fastq", verbatim_doc_comment)]
+        output_format: OutputFormat,
+
+        /// compress the output. if not given, this is chosen from the extension of the output
+        /// file: `.gz` for gzip, and `.bgz` or `.bgzf` for BGZF. compression uses every thread
+        #[arg(long, value_enum, verbatim_doc_comment)]
+        compress: Option<OutputCompression>,
+
+        #[command(flatten)]
+        split: SplitArgs,
+
         /// the number of threads to use
         #[arg(short, long, default_value_t = 4)]
         threads: usize,
@@ -124,6 +176,74 @@ pub enum Commands {
         /// for each duplicate group of reads, report the original reads along with the consensus
         #[arg(short, long, action)]
         report_original_reads: bool,
+
+        /// whether to call a consensus for each group, or to choose one of its original reads
+        #[arg(long, value_enum, default_value = "consensus")]
+        mode: crate::call::CallMode,
+
+        /// how to choose the read used with `--mode representative`
+        #[arg(long, value_enum, default_value = "quality")]
+        representative_by: crate::call::RepresentativeStrategy,
+
+        /// orient reads in each group to a common strand before calling, by reverse complementing
+        /// reads on the opposite strand. the strand of each output read, relative to the
+        /// consensus, is written as an `ST:A:` tag
+        #[arg(long, value_enum, default_value = "none", verbatim_doc_comment)]
+        orient: crate::orient::OrientMethod,
+
+        /// the primer sequence used with `--orient primer`, as it appears at the start of a
+        /// forward read
+        #[arg(long, verbatim_doc_comment)]
+        primer: Option<String>,
+
+        /// downsample groups with more than this many reads to this many reads before calling a
+        /// consensus. the group size reported in the `CON_n` tag is still the full size of the
+        /// group
+        #[arg(long, verbatim_doc_comment)]
+        max_group_size: Option<usize>,
+
+        /// how to choose the reads kept when downsampling a group
+        #[arg(long, value_enum, default_value = "quality")]
+        downsample_by: crate::call::DownsampleStrategy,
+
+        /// the seed used when downsampling groups randomly, and when sampling reads to find a medoid
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+
+        /// write a per-group QC report to this .tsv file, with the identity of each read to the
+        /// consensus, the number of consensus bases with low read support, and the mean
+        /// consensus quality. requires `--mode consensus`
+        #[arg(long, verbatim_doc_comment)]
+        qc: Option<String>,
+
+        /// save the progress of the run to `<output>.checkpoint` after each chunk of groups, so
+        /// that it can be continued with `--resume` if it is interrupted. the output must be an
+        /// uncompressed FASTQ, FASTA or SAM file. the checkpoint is removed once the run finishes
+        #[arg(long, verbatim_doc_comment)]
+        checkpoint: bool,
+
+        /// continue an interrupted run from the checkpoint saved next to `--output` by
+        /// `--checkpoint`, and keep saving checkpoints as it runs
+        #[arg(long, verbatim_doc_comment)]
+        resume: bool,
+
+        #[command(flatten)]
+        msa: MsaArgs,
+
+        #[command(flatten)]
+        alignment: AlignmentArgs,
+
+        #[command(flatten)]
+        trim: TrimArgs,
+
+        #[command(flatten)]
+        ignored: IgnoredArgs,
+
+        #[command(flatten)]
+        selection: SelectionArgs,
+
+        #[command(flatten)]
+        grouping: GroupingOpts,
     },
 
     /// Tag each read by its UMI group, and write to a .fastq file. Due to the large amounts of
@@ -139,9 +259,383 @@ pub enum Commands {
 
         #[arg(short)]
         output: Option<String>,
+
+        /// the format of the output. see `call --help` for more
+        #[arg(long, value_enum, default_value = "fastq")]
+        output_format: OutputFormat,
+
+        /// compress the output. see `call --help` for more
+        #[arg(long, value_enum)]
+        compress: Option<OutputCompression>,
+
+        #[command(flatten)]
+        split: SplitArgs,
+
+        #[command(flatten)]
+        trim: TrimArgs,
+
+        #[command(flatten)]
+        ignored: IgnoredArgs,
+
+        #[command(flatten)]
+        selection: SelectionArgs,
+
+        #[command(flatten)]
+        grouping: GroupingOpts,
+
+        /// the shell used to run the given command
+        #[arg(long, default_value = "bash")]
+        shell: String,
+
+        /// the number of threads to use. this will not guard against race conditions in any
+        /// downstream applications used. this will effectively set the number of individual
+        /// processes to launch
+        #[arg(short, long, default_value_t = 1, verbatim_doc_comment)]
+        threads: usize,
+
+        /// the command to run. each group is passed to a separate run of the command as .fastq
+        /// (or .fasta, with `--output-format fasta`) standard input, and the output of each run
+        /// is written in group order. a single argument is run as a command line by `--shell`,
+        /// so may be a pipeline; several arguments are each passed to the command as given. if
+        /// not given, the groups are written directly
+        #[arg(trailing_var_arg = true, verbatim_doc_comment)]
+        command: Vec<String>,
+    },
+
+    /// Write a table of the group which each read belongs to, using only the index
+    #[command(arg_required_else_help = true)]
+    Assign {
+        /// the index file
+        #[arg(long)]
+        index: String,
+
+        /// the output .tsv file, or default to stdout. this has a row per read, with its name,
+        /// barcode, UMI, group index (as in the `UG:i` tag) and group size
+        #[arg(short, verbatim_doc_comment)]
+        output: Option<String>,
+
+        #[command(flatten)]
+        selection: SelectionArgs,
     },
 }
 
+/// Options which control how duplicate reads are grouped together.
+#[derive(Args)]
+pub struct GroupingOpts {
+    /// group duplicates on disk, by sorting the index into temporary files in this directory.
+    /// use this when the index is too large to be grouped in memory. with `--read-by partition`,
+    /// duplicates are grouped in memory and this directory holds the partitions instead
+    #[arg(long, verbatim_doc_comment)]
+    pub tmp_dir: Option<String>,
+
+    /// the approximate amount of memory (in MB) to use when grouping duplicates on disk
+    #[arg(long, default_value_t = 1024, requires = "tmp_dir")]
+    pub memory_limit: usize,
+
+    /// how the reads of each group are read from the input. `partition` avoids random access,
+    /// which is slow on network filesystems and spinning disks, by splitting reads into
+    /// temporary files in `--tmp-dir`, or $TMPDIR if not given. these need about as much disk
+    /// space as the input. groups are then not written in input order
+    #[arg(long, value_enum, default_value = "random", verbatim_doc_comment)]
+    pub read_by: ReadStrategy,
+
+    /// the number of partitions used with `--read-by partition`. each partition is grouped in
+    /// memory, so more partitions use less memory
+    #[arg(long, default_value_t = 64, verbatim_doc_comment)]
+    pub partitions: usize,
+
+    /// the number of threads used to read groups by random access. records are read in sorted,
+    /// coalesced batches, which may be read in parallel on network or parallel filesystems
+    #[arg(long, default_value_t = 1, verbatim_doc_comment)]
+    pub io_threads: usize,
+
+    /// read groups by random access from a memory mapping of the input, rather than with a read
+    /// for each batch of records. this is usually faster for local files. the input is read as
+    /// usual if it cannot be mapped
+    #[arg(long, verbatim_doc_comment)]
+    pub mmap: bool,
+}
+
+/// Options which split the output into a directory of files.
+#[derive(Args)]
+pub struct SplitArgs {
+    /// write one file per cell barcode to the directory given by `-o`, along with a
+    /// `manifest.tsv` listing the number of reads in each file. files are compressed only if
+    /// `--compress` is given
+    #[arg(long, value_enum, verbatim_doc_comment)]
+    pub split_by: Option<SplitBy>,
+
+    /// the maximum number of output files to keep open at once with `--split-by`
+    #[arg(long, default_value_t = 256, requires = "split_by")]
+    pub max_open_files: usize,
+}
+
+/// Options which choose how a summary is written.
+#[derive(Args)]
+pub struct ReportArgs {
+    /// the format of the summary. the json and tsv formats are documented in the readme
+    #[arg(long, value_enum, default_value = "html", verbatim_doc_comment)]
+    pub format: SummaryFormat,
+
+    /// render this handlebars template instead of the built-in html report. use
+    /// --dump-context to see the data which is available to it. templates are only rendered
+    /// for the html format, so this cannot be given with --format
+    #[arg(long, conflicts_with = "format", verbatim_doc_comment)]
+    pub template: Option<String>,
+
+    /// write the data which html templates are rendered against as json, instead of a report.
+    /// this is written to standard output unless -o is given
+    #[arg(long, conflicts_with = "template", verbatim_doc_comment)]
+    pub dump_context: bool,
+}
+
+/// Options which report on the cells of a summarised index.
+#[derive(Args)]
+pub struct CellArgs {
+    /// write per-cell statistics (reads, unique UMIs, duplication rate and saturation)
+    /// to this .tsv file
+    #[arg(long, verbatim_doc_comment)]
+    pub cell_stats: Option<String>,
+
+    /// how barcodes are called as cells, from the barcode rank curve of reads per barcode
+    #[arg(long, value_enum, default_value = "knee")]
+    pub cell_calling: CellCalling,
+
+    /// the number of cells which are expected. if given, cells are called from the reads of
+    /// the expected cells instead of from the shape of the barcode rank curve
+    #[arg(
+        long,
+        verbatim_doc_comment,
+        value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub expected_cells: Option<usize>,
+
+    /// write the barcodes called as cells to this file, one per line, for use with --barcodes
+    #[arg(long)]
+    pub called_barcodes: Option<String>,
+}
+
+/// Options which restrict processing to a subset of groups.
+#[derive(Args)]
+pub struct SelectionArgs {
+    /// only process groups with one of the barcodes in this file, one per line. when any
+    /// selection is made, selected groups are read by random access, rather than by streaming
+    /// the whole input, and keep the index which they have when every group is processed
+    #[arg(long, verbatim_doc_comment)]
+    pub barcodes: Option<String>,
+
+    /// only process groups with at least this many reads
+    #[arg(long)]
+    pub select_min_size: Option<usize>,
+
+    /// only process groups with at most this many reads
+    #[arg(long)]
+    pub select_max_size: Option<usize>,
+}
+
+impl SelectionArgs {
+    /// Returns the groups which were selected.
+    pub fn selection(&self) -> Result<GroupSelection> {
+        let min_size = self.select_min_size.unwrap_or(1);
+        ensure!(
+            self.select_max_size.is_none_or(|max| max >= min_size),
+            "--select-max-size must be at least --select-min-size"
+        );
+
+        Ok(GroupSelection {
+            barcodes: self.barcodes.as_deref().map(read_barcodes).transpose()?,
+            min_size,
+            max_size: self.select_max_size,
+        })
+    }
+}
+
+/// Options which choose groups whose multiple sequence alignment is written, for debugging.
+#[derive(Args)]
+pub struct MsaArgs {
+    /// write the multiple sequence alignment of each chosen group, with its consensus, to this
+    /// directory as aligned .fasta files named `group_<index>.fasta`. requires `--mode consensus`
+    #[arg(long, verbatim_doc_comment)]
+    pub msa_dir: Option<String>,
+
+    /// the indices of groups to write, as in the `UG:i` tag, separated by commas
+    #[arg(long, value_delimiter = ',', requires = "msa_dir")]
+    pub msa_groups: Vec<usize>,
+
+    /// the identifiers of groups to write, such as `BC_UMI`, separated by commas
+    #[arg(long, value_delimiter = ',', requires = "msa_dir")]
+    pub msa_ids: Vec<String>,
+
+    /// write this many randomly chosen groups with more than one read, using `--seed`
+    #[arg(long, requires = "msa_dir")]
+    pub msa_sample: Option<usize>,
+}
+
+impl MsaArgs {
+    /// Returns the MSA options, if an MSA directory was given.
+    pub fn opts(&self, seed: u64) -> Result<Option<MsaOpts>> {
+        let Some(dir) = &self.msa_dir else {
+            return Ok(None);
+        };
+        ensure!(
+            !self.msa_groups.is_empty() || !self.msa_ids.is_empty() || self.msa_sample.is_some(),
+            "--msa-dir requires --msa-groups, --msa-ids or --msa-sample"
+        );
+
+        Ok(Some(MsaOpts {
+            dir: dir.clone(),
+            groups: self.msa_groups.clone(),
+            ids: self.msa_ids.clone(),
+            sample: self.msa_sample,
+            seed,
+        }))
+    }
+}
+
+/// Options which control how reads which were filtered out during indexing are reported.
+#[derive(Args)]
+pub struct IgnoredArgs {
+    /// pass reads which were filtered out during indexing through to the output, in their
+    /// input order, tagged with `UT:Z:IGN`
+    #[arg(long, verbatim_doc_comment)]
+    pub include_ignored: bool,
+
+    /// write reads which were filtered out during indexing to this separate .fastq file instead,
+    /// tagged with `UT:Z:IGN`
+    #[arg(long, conflicts_with = "include_ignored", verbatim_doc_comment)]
+    pub ignored_output: Option<String>,
+}
+
+/// Options which control how reads are trimmed before consensus calling.
+#[derive(Args)]
+pub struct TrimArgs {
+    /// trim the adapter and primer sequences in this .fasta file from both ends of each read,
+    /// in either orientation. the trimmed region of each original read is written as a
+    /// `TR:Z:start-end` tag, in 0-based, end-exclusive coordinates
+    #[arg(long, verbatim_doc_comment)]
+    pub adapters: Option<String>,
+
+    /// trim polyA tails from the end, and polyT tails from the start, of each read
+    #[arg(long)]
+    pub trim_poly_tails: bool,
+
+    /// write original reads trimmed, instead of untrimmed
+    #[arg(long)]
+    pub trim_originals: bool,
+}
+
+impl TrimArgs {
+    /// Returns the trimming options, reading adapter sequences from the adapter file if given.
+    pub fn opts(&self) -> Result<TrimOpts> {
+        let adapters = match &self.adapters {
+            Some(path) => read_adapters(path)?,
+            None => Vec::new(),
+        };
+
+        let opts = TrimOpts {
+            adapters,
+            poly_tails: self.trim_poly_tails,
+            trim_originals: self.trim_originals,
+        };
+        ensure!(
+            !opts.trim_originals || opts.enabled(),
+            "--trim-originals requires --adapters or --trim-poly-tails"
+        );
+
+        Ok(opts)
+    }
+}
+
+/// Options which control the alignment parameters used for consensus calling.
+#[derive(Args)]
+pub struct AlignmentArgs {
+    /// the preset alignment parameters to use, tuned for each sequencing technology.
+    /// any of the options below will override the value given by the profile
+    #[arg(long, value_enum, default_value = "ont-r10", verbatim_doc_comment)]
+    pub poa_profile: PoaProfile,
+
+    /// the type of alignment used to add each read to the consensus graph
+    #[arg(long, value_enum)]
+    pub alignment: Option<AlignmentMode>,
+
+    /// the score for a matching base (non-negative)
+    #[arg(long, allow_negative_numbers = true)]
+    pub match_score: Option<i8>,
+
+    /// the score for a mismatching base (non-positive)
+    #[arg(long, allow_negative_numbers = true)]
+    pub mismatch_score: Option<i8>,
+
+    /// the score for opening a gap (non-positive). each gap is scored by this and
+    /// `--gap-extend`, or by `--gap-open-2` and `--gap-extend-2`, whichever is higher
+    #[arg(long, allow_negative_numbers = true, verbatim_doc_comment)]
+    pub gap_open: Option<i8>,
+
+    /// the score for extending a gap (non-positive)
+    #[arg(long, allow_negative_numbers = true)]
+    pub gap_extend: Option<i8>,
+
+    /// the score for opening a gap with the second pair of gap scores, which is usually
+    /// lower than `--gap-open`, so that long gaps are penalised less per base (non-positive)
+    #[arg(long, allow_negative_numbers = true, verbatim_doc_comment)]
+    pub gap_open_2: Option<i8>,
+
+    /// the score for extending a gap with the second pair of gap scores (non-positive)
+    #[arg(long, allow_negative_numbers = true)]
+    pub gap_extend_2: Option<i8>,
+}
+
+impl AlignmentArgs {
+    /// Returns the alignment parameters of the chosen profile, with any overrides applied.
+    pub fn params(&self) -> AlignmentParams {
+        let mut params = self.poa_profile.params();
+
+        if let Some(v) = self.alignment {
+            params.alignment = v;
+        }
+        if let Some(v) = self.match_score {
+            params.match_score = v;
+        }
+        if let Some(v) = self.mismatch_score {
+            params.mismatch_score = v;
+        }
+        if let Some(v) = self.gap_open {
+            params.gap_open = v;
+        }
+        if let Some(v) = self.gap_extend {
+            params.gap_extend = v;
+        }
+        if let Some(v) = self.gap_open_2 {
+            params.gap_open_2 = v;
+        }
+        if let Some(v) = self.gap_extend_2 {
+            params.gap_extend_2 = v;
+        }
+
+        // the second pair of gap scores still applies to long gaps, which the user may not expect
+        let first_pair = self.gap_open.is_some() || self.gap_extend.is_some();
+        let second_pair = self.gap_open_2.is_some() || self.gap_extend_2.is_some();
+        if first_pair && !second_pair {
+            warn!(
+                "Long gaps are still scored by the profile's second pair of gap scores ({}, {}); \
+                 set --gap-open-2 and --gap-extend-2 to change these",
+                params.gap_open_2, params.gap_extend_2
+            );
+        }
+
+        params
+    }
+}
+
+/// Parses the amount of additional sequencing given to `summary --extra-depth`, which must be a
+/// finite number greater than zero.
+fn parse_extra_depth(arg: &str) -> Result<f64, String> {
+    match arg.parse::<f64>() {
+        Ok(v) if v.is_finite() && v > 0.0 => Ok(v),
+        _ => Err(format!("expected a number greater than 0, got '{arg}'")),
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct ArgInterval {
     pub min: f64,
@@ -120,6 +120,15 @@ impl RecordIdentifier {
     }
 }
 
+/// Statistics about the groups of duplicate reads in an index.
+///
+/// # Fields
+///
+/// * `total_reads` - The number of reads which were grouped
+/// * `duplicate_reads` - The number of reads in groups of more than one read
+/// * `duplicate_ids` - The number of groups of more than one read
+/// * `proportion_duplicate` - `duplicate_reads / total_reads`
+/// * `distribution` - The number of groups of each size, by size
 #[derive(Serialize, Debug)]
 pub struct DuplicateStatistics {
     pub total_reads: usize,
@@ -129,6 +138,101 @@ pub struct DuplicateStatistics {
     pub distribution: BTreeMap<usize, usize>,
 }
 
+impl DuplicateStatistics {
+    pub fn new() -> Self {
+        DuplicateStatistics {
+            total_reads: 0,
+            duplicate_reads: 0,
+            duplicate_ids: 0,
+            proportion_duplicate: 0.0,
+            distribution: BTreeMap::new(),
+        }
+    }
+
+    /// Records a group of `length` reads. Single reads are not recorded in the distribution
+    /// until `finish` is called.
+    pub fn add_group(&mut self, length: usize) {
+        if length > 1 {
+            self.duplicate_ids += 1;
+            self.duplicate_reads += length;
+            *self.distribution.entry(length).or_insert(0) += 1;
+        }
+    }
+
+    /// Computes the single read count and the duplicate proportion, once every group
+    /// has been added.
+    pub fn finish(&mut self) {
+        self.distribution
+            .insert(1, self.total_reads - self.duplicate_reads);
+
+        self.proportion_duplicate = self.duplicate_reads as f64 / self.total_reads as f64;
+    }
+
+    /// Returns the number of unique molecules, i.e. the number of groups of any size.
+    pub fn unique_molecules(&self) -> usize {
+        self.distribution.values().sum()
+    }
+
+    /// Returns the sequencing saturation, `1 - unique molecules / total reads`. This is the
+    /// probability that sequencing one more read finds a molecule which has already been seen.
+    pub fn saturation(&self) -> f64 {
+        1.0 - (self.unique_molecules() as f64 / self.total_reads as f64)
+    }
+
+    /// Estimates the number of new unique molecules which would be found by sequencing `t` times
+    /// as many additional reads, using the group size distribution. For example, `t = 1` estimates
+    /// the new molecules found by another lane of the same depth.
+    ///
+    /// This uses the Good-Toulmin estimator, which is the basis of the extrapolation in preseq.
+    /// For `t > 1` the series is smoothed with binomial weights as described by Orlitsky, Suresh
+    /// and Wu (2016), since the raw series does not converge.
+    pub fn extrapolate_unique(&self, t: f64) -> f64 {
+        let n = self.total_reads as f64;
+
+        // weights[i] is the probability that the smoothing variable L is at least i
+        let weights: Vec<f64> = if t <= 1.0 {
+            Vec::new()
+        } else {
+            let k = (0.5 * (n * t.powi(2) / (t - 1.0)).log(3.0)).ceil().max(1.0) as usize;
+            let q = 2.0 / (t + 2.0);
+
+            // binomial probability mass function of Bin(k, q)
+            let mut pmf = vec![0.0; k + 1];
+            pmf[0] = (1.0 - q).powi(k as i32);
+            for j in 1..=k {
+                pmf[j] = pmf[j - 1] * ((k - j + 1) as f64 / j as f64) * (q / (1.0 - q));
+            }
+
+            // P(L >= i) for i = 0..=k
+            let mut tail = vec![0.0; k + 1];
+            let mut acc = 0.0;
+            for j in (0..=k).rev() {
+                acc += pmf[j];
+                tail[j] = acc;
+            }
+            tail
+        };
+
+        self.distribution
+            .iter()
+            .map(|(&size, &count)| {
+                let weight = if t <= 1.0 {
+                    1.0
+                } else {
+                    weights.get(size).copied().unwrap_or(0.0)
+                };
+                if weight == 0.0 {
+                    return 0.0;
+                }
+
+                // the sign alternates with each group size
+                let sign = if size % 2 == 1 { 1.0 } else { -1.0 };
+                sign * t.powi(size as i32) * weight * count as f64
+            })
+            .sum()
+    }
+}
+
 impl IndexReader {
     /// Reads a FASTQ index file and identifies duplicate records.
     ///
@@ -151,13 +255,7 @@ impl IndexReader {
 
         let mut map = DuplicateMap::new();
 
-        let mut stats = DuplicateStatistics {
-            total_reads: 0,
-            duplicate_reads: 0,
-            duplicate_ids: 0,
-            proportion_duplicate: 0.0,
-            distribution: BTreeMap::new(),
-        };
+        let mut stats = DuplicateStatistics::new();
 
         // Parse each row of the reader
         for read in self.index_records()? {
@@ -174,32 +272,10 @@ impl IndexReader {
         map.shrink_to_fit(); // optimise memory usage
 
         // Compute information about the duplicates
-        stats.duplicate_ids = 0;
-        stats.duplicate_reads = map
-            .by_id
-            .values()
-            .map(|v| {
-                let length = v.len();
-                if length > 1 {
-                    stats.duplicate_ids += 1;
-
-                    if let Some(x) = stats.distribution.get_mut(&length) {
-                        *x += 1
-                    } else {
-                        stats.distribution.insert(length, 1);
-                    }
-                    length
-                } else {
-                    0
-                }
-            })
-            .sum();
-
-        stats
-            .distribution
-            .insert(1, stats.total_reads - stats.duplicate_reads);
-
-        stats.proportion_duplicate = stats.duplicate_reads as f64 / stats.total_reads as f64;
+        for v in map.by_id.values() {
+            stats.add_group(v.len());
+        }
+        stats.finish();
 
         info!("Generated duplicate map from index file");
 
@@ -0,0 +1,386 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Seek, SeekFrom};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use csv::{DeserializeRecordsIntoIter, ReaderBuilder, WriterBuilder};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tempfile::tempfile_in;
+
+use crate::duplicates::{DuplicateStatistics, RecordIdentifier, RecordPosition};
+use crate::index::{IndexReader, IndexRecord};
+
+/// Options for grouping duplicate reads on disk, instead of in memory.
+///
+/// # Fields
+///
+/// * `tmp_dir` - The directory in which the temporary sorted runs are created
+/// * `memory_limit` - The approximate number of bytes to buffer before a run is written to disk
+#[derive(Clone, Debug)]
+pub struct ExternalSortOpts {
+    pub tmp_dir: PathBuf,
+    pub memory_limit: usize,
+}
+
+/// A single index record, as stored in the first set of sorted runs. The derived ordering sorts
+/// by identifier first and then by position, so that the members of a group are adjacent and
+/// in file order.
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct SortEntry {
+    head: String,
+    tail: String,
+    pos: usize,
+    length: usize,
+}
+
+/// A complete group, as stored in the second set of sorted runs. The derived ordering sorts
+/// by the position of the first read in the group, which is the order in which the in-memory
+/// `DuplicateMap` visits groups.
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct GroupEntry {
+    first_pos: usize,
+    head: String,
+    tail: String,
+    /// the positions of every read in the group, as `pos:length` pairs separated by commas
+    positions: String,
+}
+
+impl GroupEntry {
+    fn new(id: RecordIdentifier, group: &[RecordPosition]) -> Self {
+        let positions = group
+            .iter()
+            .map(|p| format!("{}:{}", p.pos, p.length))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        GroupEntry {
+            first_pos: group[0].pos,
+            head: id.head,
+            tail: id.tail,
+            positions,
+        }
+    }
+
+    fn into_group(self) -> Result<(RecordIdentifier, Vec<RecordPosition>)> {
+        let positions = self
+            .positions
+            .split(',')
+            .map(|p| {
+                let (pos, length) = p.split_once(':').context("Invalid position in run")?;
+                Ok(RecordPosition {
+                    pos: pos.parse()?,
+                    length: length.parse()?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let id = RecordIdentifier {
+            head: self.head,
+            tail: self.tail,
+        };
+
+        Ok((id, positions))
+    }
+}
+
+/// Buffers items in memory, writing them out as a sorted run to a temporary file whenever the
+/// memory limit is reached.
+struct RunSorter<T> {
+    opts: ExternalSortOpts,
+    buf: Vec<T>,
+    buf_bytes: usize,
+    runs: Vec<File>,
+}
+
+impl<T: Serialize + DeserializeOwned + Ord> RunSorter<T> {
+    fn new(opts: &ExternalSortOpts) -> Self {
+        RunSorter {
+            opts: opts.clone(),
+            buf: Vec::new(),
+            buf_bytes: 0,
+            runs: Vec::new(),
+        }
+    }
+
+    /// Add an item which occupies approximately `size` bytes of heap memory.
+    fn push(&mut self, item: T, size: usize) -> Result<()> {
+        self.buf.push(item);
+        self.buf_bytes += size + std::mem::size_of::<T>();
+
+        if self.buf_bytes >= self.opts.memory_limit {
+            self.write_run()?;
+        }
+        Ok(())
+    }
+
+    /// Sort the buffered items and write them to a new temporary file.
+    fn write_run(&mut self) -> Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        self.buf.sort_unstable();
+
+        // the OS will clean up this file once it is dropped
+        let mut file = tempfile_in(&self.opts.tmp_dir).with_context(|| {
+            format!(
+                "Could not create a temporary file in {}",
+                self.opts.tmp_dir.display()
+            )
+        })?;
+
+        let mut wtr = WriterBuilder::new()
+            .delimiter(b'\t')
+            .has_headers(false)
+            .from_writer(BufWriter::new(file.try_clone()?));
+
+        for item in self.buf.drain(..) {
+            wtr.serialize(item)?;
+        }
+        wtr.flush()?;
+        drop(wtr);
+
+        file.seek(SeekFrom::Start(0))?;
+        self.runs.push(file);
+        self.buf_bytes = 0;
+
+        Ok(())
+    }
+
+    /// Write any remaining items, and merge every run into a single sorted stream.
+    fn finish(mut self) -> Result<RunMerger<T>> {
+        self.write_run()?;
+        debug!("Merging {} sorted runs", self.runs.len());
+        RunMerger::new(self.runs)
+    }
+}
+
+/// Performs a k-way merge over a set of sorted runs.
+struct RunMerger<T> {
+    runs: Vec<DeserializeRecordsIntoIter<BufReader<File>, T>>,
+    heap: BinaryHeap<Reverse<(T, usize)>>,
+}
+
+impl<T: DeserializeOwned + Ord> RunMerger<T> {
+    fn new(files: Vec<File>) -> Result<Self> {
+        let mut merger = RunMerger {
+            runs: Vec::with_capacity(files.len()),
+            heap: BinaryHeap::with_capacity(files.len()),
+        };
+
+        for file in files {
+            let rdr = ReaderBuilder::new()
+                .delimiter(b'\t')
+                .has_headers(false)
+                .from_reader(BufReader::new(file));
+
+            merger.runs.push(rdr.into_deserialize());
+            merger.advance(merger.runs.len() - 1)?;
+        }
+
+        Ok(merger)
+    }
+
+    /// Read the next item of a run into the heap, if there is one.
+    fn advance(&mut self, run: usize) -> Result<()> {
+        if let Some(item) = self.runs[run].next() {
+            let item = item.context("Could not read from temporary sorted run")?;
+            self.heap.push(Reverse((item, run)));
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Option<T>> {
+        let Some(Reverse((item, run))) = self.heap.pop() else {
+            return Ok(None);
+        };
+        self.advance(run)?;
+        Ok(Some(item))
+    }
+}
+
+/// A stream of duplicate groups read back from disk, in the order of the first read of each
+/// group. This is the same order in which the in-memory `DuplicateMap` stores its groups.
+pub struct ExternalGroups {
+    merger: RunMerger<GroupEntry>,
+}
+
+impl ExternalGroups {
+    /// Returns the next group, along with the positions of each of its reads in file order.
+    pub fn next_group(&mut self) -> Result<Option<(RecordIdentifier, Vec<RecordPosition>)>> {
+        self.merger.next()?.map(GroupEntry::into_group).transpose()
+    }
+}
+
+impl IndexReader {
+    /// Reads a FASTQ index file and identifies duplicate records, without holding every record
+    /// in memory at once. This is the disk-backed equivalent of `get_duplicates`.
+    ///
+    /// The index is first externally sorted by identifier, so that reads in the same group are
+    /// adjacent. Each group is then externally sorted by the position of its first read, so that
+    /// groups can be streamed in the same order as the input file.
+    ///
+    /// # Arguments
+    ///
+    /// * `opts` - The temporary directory and memory limit to use for the sorted runs.
+    ///
+    /// # Returns
+    ///
+    /// A tuple containing:
+    /// - `ExternalGroups`: A stream of the groups found, in order of first appearance.
+    /// - `DuplicateStatistics`: Statistics about the duplicates found.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the index file cannot be read, or if the
+    /// temporary runs cannot be written to or read from `opts.tmp_dir`.
+    pub fn get_duplicates_external(
+        &mut self,
+        opts: &ExternalSortOpts,
+    ) -> Result<(ExternalGroups, DuplicateStatistics)> {
+        info!(
+            "Reading index file, using {} for temporary files...",
+            opts.tmp_dir.display()
+        );
+
+        let mut stats = DuplicateStatistics::new();
+
+        // sort every record by its identifier
+        let mut by_id = RunSorter::new(opts);
+        for read in self.index_records()? {
+            let record: IndexRecord = read?;
+            if record.ignored {
+                continue;
+            }
+
+            stats.total_reads += 1;
+
+            let id = RecordIdentifier::from_string(&record.id);
+            let size = id.head.len() + id.tail.len();
+            by_id.push(
+                SortEntry {
+                    head: id.head,
+                    tail: id.tail,
+                    pos: record.pos,
+                    length: record.rec_len,
+                },
+                size,
+            )?;
+        }
+        let mut by_id = by_id.finish()?;
+
+        // collect adjacent records into groups, then sort the groups by their first position
+        let mut by_pos = RunSorter::new(opts);
+        let mut current: Option<(RecordIdentifier, Vec<RecordPosition>)> = None;
+
+        loop {
+            let entry = by_id.next()?;
+
+            // check if this entry starts a new group
+            let same_group = match (&current, &entry) {
+                (Some((id, _)), Some(e)) => id.head == e.head && id.tail == e.tail,
+                _ => false,
+            };
+
+            if !same_group {
+                if let Some((id, positions)) = current.take() {
+                    stats.add_group(positions.len());
+
+                    let group = GroupEntry::new(id, &positions);
+                    let size = group.head.len() + group.tail.len() + group.positions.len();
+                    by_pos.push(group, size)?;
+                }
+            }
+
+            let Some(entry) = entry else {
+                break;
+            };
+
+            let rec_pos = RecordPosition {
+                pos: entry.pos,
+                length: entry.length,
+            };
+
+            match current {
+                Some((_, ref mut positions)) => positions.push(rec_pos),
+                None => {
+                    let id = RecordIdentifier {
+                        head: entry.head,
+                        tail: entry.tail,
+                    };
+                    current = Some((id, vec![rec_pos]))
+                }
+            }
+        }
+
+        stats.finish();
+
+        info!("Generated duplicate groups from index file");
+
+        let groups = ExternalGroups {
+            merger: by_pos.finish()?,
+        };
+        Ok((groups, stats))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(head: &str, pos: usize) -> SortEntry {
+        SortEntry {
+            head: head.into(),
+            tail: "UMI".into(),
+            pos,
+            length: 100,
+        }
+    }
+
+    #[test]
+    fn merges_several_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let opts = ExternalSortOpts {
+            tmp_dir: dir.path().into(),
+            memory_limit: 3 * std::mem::size_of::<SortEntry>(),
+        };
+
+        // three entries fill each run, so these are written as four runs
+        let mut sorter = RunSorter::new(&opts);
+        for (i, head) in ["G", "C", "A", "T", "A", "G", "C", "T", "C", "A", "G"]
+            .iter()
+            .enumerate()
+        {
+            sorter.push(entry(head, i), 0).unwrap();
+        }
+        sorter.write_run().unwrap();
+        assert_eq!(sorter.runs.len(), 4);
+
+        let mut merger = sorter.finish().unwrap();
+        let mut merged = Vec::new();
+        while let Some(e) = merger.next().unwrap() {
+            merged.push((e.head, e.pos));
+        }
+
+        let expected: Vec<(String, usize)> = [
+            ("A", 2),
+            ("A", 4),
+            ("A", 9),
+            ("C", 1),
+            ("C", 6),
+            ("C", 8),
+            ("G", 0),
+            ("G", 5),
+            ("G", 10),
+            ("T", 3),
+            ("T", 7),
+        ]
+        .iter()
+        .map(|&(h, p)| (h.to_string(), p))
+        .collect();
+        assert_eq!(merged, expected);
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::fs::File;
+
+use anyhow::{Context, Result};
+use memmap2::Mmap;
+use needletail::parser::FastqReader;
+use needletail::FastxReader;
+use rayon::prelude::*;
+use rayon::ThreadPool;
+
+use crate::duplicates::RecordPosition;
+use crate::io::Record;
+
+/// Records which are at most this many bytes apart are read in a single range, as reading the
+/// bytes in between is cheaper than another seek.
+const MAX_GAP: usize = 64 * 1024;
+
+/// The maximum number of bytes read in a single range.
+const MAX_RANGE: usize = 8 * 1024 * 1024;
+
+/// A contiguous range of the input file, holding one or more records.
+struct Range {
+    start: usize,
+    end: usize,
+    positions: Vec<RecordPosition>,
+}
+
+/// Groups records into ranges to be read at once, in order of their offset.
+fn coalesce(mut positions: Vec<RecordPosition>) -> Vec<Range> {
+    positions.sort_unstable_by_key(|p| p.pos);
+
+    let mut ranges: Vec<Range> = Vec::new();
+    for pos in positions {
+        let end = pos.pos + pos.length;
+        match ranges.last_mut() {
+            Some(range) if pos.pos <= range.end + MAX_GAP && end - range.start <= MAX_RANGE => {
+                range.end = range.end.max(end);
+                range.positions.push(pos);
+            }
+            _ => ranges.push(Range {
+                start: pos.pos,
+                end,
+                positions: vec![pos],
+            }),
+        }
+    }
+    ranges
+}
+
+/// Reads exactly `buf.len()` bytes at `offset`, without moving the file cursor, so that a file
+/// can be read from several threads at once.
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parses a single FASTQ record from its raw bytes, as they appear in the input file.
+pub fn parse_record(bytes: &[u8]) -> Result<Record> {
+    let mut fq_reader = FastqReader::new(bytes);
+    let rec = fq_reader.next().context("Unexpected EOF")??;
+    Record::try_from(rec).context("Could not perform utf8 conversions")
+}
+
+/// Returns the raw bytes of a record from a memory mapping of the input file.
+pub fn mapped_record<'a>(map: &'a Mmap, pos: &RecordPosition) -> Result<&'a [u8]> {
+    map.get(pos.pos..pos.pos + pos.length).with_context(|| {
+        format!(
+            "Could not read {} bytes at position {}, past the end of the input file",
+            pos.length, pos.pos
+        )
+    })
+}
+
+/// Reads a range, and parses each of its records.
+fn read_range(file: &File, range: &Range) -> Result<Vec<(usize, Record)>> {
+    let mut bytes = vec![0; range.end - range.start];
+    read_at(file, &mut bytes, range.start as u64).with_context(|| {
+        format!(
+            "Could not read {} bytes at position {}",
+            bytes.len(),
+            range.start
+        )
+    })?;
+
+    range
+        .positions
+        .iter()
+        .map(|pos| {
+            let offset = pos.pos - range.start;
+            let rec = parse_record(&bytes[offset..offset + pos.length])?;
+            Ok((pos.pos, rec))
+        })
+        .collect()
+}
+
+/// Reads many records from the input file at once. The records are sorted by their offset and
+/// read in coalesced ranges, rather than seeking to each record in turn.
+///
+/// # Arguments
+///
+/// * `file` - The input file.
+/// * `positions` - The positions of the records to read.
+/// * `pool` - If set, the ranges are read in parallel on this thread pool.
+///
+/// # Returns
+///
+/// The records which were read, by their position in the input file.
+pub fn read_records(
+    file: &File,
+    positions: Vec<RecordPosition>,
+    pool: Option<&ThreadPool>,
+) -> Result<HashMap<usize, Record>> {
+    let ranges = coalesce(positions);
+
+    let records: Vec<Vec<(usize, Record)>> = match pool {
+        Some(pool) => pool.install(|| {
+            ranges
+                .par_iter()
+                .map(|range| read_range(file, range))
+                .collect::<Result<_>>()
+        })?,
+        None => ranges
+            .iter()
+            .map(|range| read_range(file, range))
+            .collect::<Result<_>>()?,
+    };
+
+    Ok(records.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(pos: usize, length: usize) -> RecordPosition {
+        RecordPosition { pos, length }
+    }
+
+    #[test]
+    fn coalesce_nearby_records() {
+        let ranges = coalesce(vec![
+            position(MAX_GAP * 10, 100),
+            position(0, 100),
+            position(100 + MAX_GAP, 50),
+        ]);
+
+        // the first two records are close enough to be read at once, but not the third
+        assert_eq!(ranges.len(), 2);
+        assert_eq!((ranges[0].start, ranges[0].end), (0, 150 + MAX_GAP));
+        assert_eq!(ranges[0].positions.len(), 2);
+        assert_eq!(
+            (ranges[1].start, ranges[1].end),
+            (MAX_GAP * 10, MAX_GAP * 10 + 100)
+        );
+    }
+
+    #[test]
+    fn coalesce_limits_range_size() {
+        let length = MAX_RANGE / 4;
+        let positions = (0..8).map(|i| position(i * length, length)).collect();
+        let ranges = coalesce(positions);
+
+        assert_eq!(ranges.len(), 2);
+        assert!(ranges.iter().all(|r| r.end - r.start <= MAX_RANGE));
+        assert!(ranges.iter().all(|r| r.positions.len() == 4));
+    }
+}
@@ -1,5 +1,20 @@
 use serde::{Deserialize, Serialize};
 
+/// Information about an input file, recorded when it is indexed.
+///
+/// # Fields
+///
+/// * `nailpolish_version` - The version of nailpolish which created the index
+/// * `file_path` - The path of the input file
+/// * `index_date` - When the index was created, in RFC 3339 format
+/// * `elapsed` - How long indexing took, in seconds
+/// * `gb` - The size of the input file, in GB
+/// * `matched_read_count` - The number of reads whose barcode and UMI were found
+/// * `unmatched_read_count` - The number of reads whose barcode and UMI were not found
+/// * `read_count` - The total number of reads
+/// * `avg_qual` - The mean total PHRED quality of the matched reads
+/// * `avg_len` - The mean length of the matched reads
+/// * `filtered_reads` - The number of matched reads which were filtered out by length or quality
 #[derive(Serialize, Deserialize, Default)]
 pub struct ReadFileMetadata {
     pub nailpolish_version: String,
@@ -1,45 +1,259 @@
 use crate::duplicates::DuplicateMap;
-use crate::io::{ReadType, UMIGroupCollection};
+use crate::io::{IgnoredOutput, ReadType, UMIGroup, UMIGroupCollection};
+use crate::output::{OutputFormat, OutputWriter};
+use crate::trim::{trim_region, TrimOpts};
 
 use std::io::prelude::*;
+use std::process::{Command, Stdio};
 
-use anyhow::Result;
+use anyhow::{ensure, Context, Result};
+use rayon::prelude::*;
+
+/// The number of groups whose records are read from the input at once.
+const BATCH_SIZE: usize = 1000;
+
+/// Joins the words of a command into a single shell command line. A single word is used as the
+/// command line itself, so that pipelines can be given as one argument. Otherwise, each word is
+/// quoted for the shell, so that it is passed to the command as given, even if it contains
+/// spaces, quotes or characters such as `$`.
+///
+/// # Arguments
+///
+/// * `words` - The program to run, followed by its arguments.
+pub fn shell_command(words: &[String]) -> String {
+    if let [line] = words {
+        return line.clone();
+    }
+
+    words
+        .iter()
+        .map(|word| {
+            let plain = !word.is_empty()
+                && word
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"-_./=:,+@%".contains(&b));
+            if plain {
+                word.clone()
+            } else {
+                format!("'{}'", word.replace('\'', "'\\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// An external command which each group of reads is piped to.
+///
+/// # Fields
+///
+/// * `shell` - The shell used to run the command, as `<shell> -c <command>`
+/// * `command` - The command to run
+/// * `threads` - The maximum number of commands to run at once
+pub struct GroupCommand {
+    pub shell: String,
+    pub command: String,
+    pub threads: usize,
+}
+
+impl GroupCommand {
+    /// Runs the command with `input` as its standard input, returning its standard output. The
+    /// standard error of the command is passed through.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the command cannot be started, or if it exits with
+    /// a non-zero status.
+    fn run(&self, input: &[u8]) -> Result<Vec<u8>> {
+        let mut child = Command::new(&self.shell)
+            .arg("-c")
+            .arg(&self.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .with_context(|| format!("Could not run `{}` with {}", self.command, self.shell))?;
+
+        // write the input on another thread, so that the command cannot block on a full stdout
+        // pipe while we are blocked writing its stdin
+        let mut stdin = child.stdin.take().expect("stdin should be piped");
+        let output = std::thread::scope(|scope| {
+            let writer = scope.spawn(move || stdin.write_all(input));
+            let output = child.wait_with_output();
+
+            // a command which exits without reading all of its input is not an error
+            match writer.join().expect("stdin writer should not panic") {
+                Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => Err(e),
+                _ => output,
+            }
+        })?;
+
+        ensure!(
+            output.status.success(),
+            "`{}` exited with {}",
+            self.command,
+            output.status
+        );
+        Ok(output.stdout)
+    }
+}
+
+/// Adds the `UG`/`UT` metadata, and the trimmed region if trimming is enabled, to each read of a
+/// group.
+fn tag_group(group: &mut UMIGroup, trim: &TrimOpts) {
+    if group.ignore {
+        group.records[0].add_metadata(group.index, ReadType::Ignored, 1, 1, 0.0);
+        return;
+    }
+
+    let group_size = group.records.len();
+    for (idx, rec) in group.records.iter_mut().enumerate() {
+        rec.add_metadata(group.index, ReadType::Original, idx + 1, group_size, 0.0);
+        if trim.enabled() {
+            let region = trim_region(rec.seq.as_bytes(), trim);
+            rec.add_tag("TR:Z", region);
+            if trim.trim_originals {
+                *rec = rec.trimmed(&region);
+            }
+        }
+    }
+}
 
 /// Adds tags to duplicate reads from the input to show what group they are in.
 ///
 /// # Arguments
 ///
 /// * `input` - A string slice that holds the name of the input file.
-/// * `writer` - A mutable reference to an object that implements the `Write` trait, used to write the output.
+/// * `writer` - The `OutputWriter` used to write the output.
 /// * `duplicates` - A `DuplicateMap` containing the duplicate reads.
+/// * `trim` - Options which control how reads are trimmed. If trimming is enabled, the trimmed
+///   region of each read is added as a tag.
+/// * `ignored` - Where reads which were filtered out during indexing are written, if at all.
 ///
 /// # Returns
 ///
 /// * `Result<()>` - Returns `Ok(())` if successful, or an error if an error occurs during processing.
-pub fn group(collection: &mut UMIGroupCollection, writer: &mut impl Write) -> Result<()> {
-    let mut duplicate_iterator = collection.stream_iter(false);
+pub fn group(
+    collection: &mut UMIGroupCollection,
+    writer: &mut OutputWriter<impl Write>,
+    trim: &TrimOpts,
+    ignored: &mut IgnoredOutput,
+) -> Result<()> {
+    let mut duplicate_iterator = collection.stream_iter(false, ignored.include());
+    duplicate_iterator.batch(BATCH_SIZE);
 
     let mut count = 0usize;
 
-    let mut first = true;
     while let Some(mut group) = duplicate_iterator.next()? {
         count += 1;
-        if count % 500000 == 0 {
+        if count.is_multiple_of(500_000) {
             info!("Processed: {} reads", count);
         }
 
-        let group_size = group.records.len();
-        for (idx, rec) in group.records.iter_mut().enumerate() {
-            if first {
-                first = false
-            } else {
-                writer.write_all(b"\n")?
+        tag_group(&mut group, trim);
+
+        for rec in group.records.iter() {
+            match ignored {
+                IgnoredOutput::Separate(ignored_writer) if group.ignore => {
+                    ignored_writer.write(rec, &group.id)?
+                }
+                _ => writer.write(rec, &group.id)?,
             }
+        }
+    }
 
-            rec.add_metadata(group.index, ReadType::Original, idx + 1, group_size, 0.0);
-            rec.write_fastq(writer)?;
+    Ok(())
+}
+
+/// Pipes each group of reads to an external command, and writes the output of each command in
+/// group order.
+///
+/// Groups are processed in chunks, with up to `command.threads` commands running at once. The
+/// run stops at the first command which fails.
+///
+/// # Arguments
+///
+/// * `collection` - The groups of duplicate reads.
+/// * `writer` - Where the output of each command is written.
+/// * `format` - The format in which each group is passed to the command, either FASTQ or FASTA.
+/// * `command` - The command to run for each group.
+/// * `trim` - Options which control how reads are trimmed.
+/// * `ignored` - Where reads which were filtered out during indexing are written, if at all. If
+///   they are included, each is passed to the command as its own group.
+pub fn group_command(
+    collection: &mut UMIGroupCollection,
+    writer: &mut impl Write,
+    format: OutputFormat,
+    command: &GroupCommand,
+    trim: &TrimOpts,
+    ignored: &mut IgnoredOutput,
+) -> Result<()> {
+    ensure!(
+        matches!(format, OutputFormat::Fastq | OutputFormat::Fasta),
+        "Groups can only be passed to a command as FASTQ or FASTA"
+    );
+    info!(
+        "Running `{}` for each group, with up to {} at once",
+        command.command, command.threads
+    );
+
+    let mut duplicate_iterator = collection.stream_iter(false, ignored.include());
+    duplicate_iterator.batch(BATCH_SIZE);
+    let chunk_size = 4 * command.threads;
+
+    let mut chunk: Vec<(UMIGroup, Vec<u8>)> = Vec::with_capacity(chunk_size);
+    let mut count = 0usize;
+    let mut finished = false;
+
+    while !finished {
+        match duplicate_iterator.next()? {
+            Some(mut group) => {
+                count += 1;
+                if count.is_multiple_of(500_000) {
+                    info!("Processed: {} groups", count);
+                }
+
+                tag_group(&mut group, trim);
+                if let (IgnoredOutput::Separate(ignored_writer), true) =
+                    (&mut *ignored, group.ignore)
+                {
+                    ignored_writer.write(&group.records[0], &group.id)?;
+                    continue;
+                }
+
+                // each record ends with a newline, as expected by most tools
+                let mut input = Vec::new();
+                for rec in group.records.iter() {
+                    match format {
+                        OutputFormat::Fasta => rec.write_fasta(&mut input)?,
+                        _ => rec.write_fastq(&mut input)?,
+                    }
+                    input.push(b'\n');
+                }
+                chunk.push((group, input));
+            }
+            None => finished = true,
+        }
+
+        if chunk.len() == chunk_size || (finished && !chunk.is_empty()) {
+            let outputs: Vec<Result<Vec<u8>>> = chunk
+                .par_iter()
+                .map(|(group, input)| {
+                    command.run(input).with_context(|| {
+                        format!(
+                            "Command failed on group {} (UG:i:{})",
+                            group.id, group.index
+                        )
+                    })
+                })
+                .collect();
+
+            for output in outputs {
+                writer.write_all(&output?)?;
+            }
+            chunk.clear();
         }
     }
 
+    writer.flush()?;
     Ok(())
 }
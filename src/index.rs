@@ -28,6 +28,10 @@ pub struct IndexRecord {
     pub n_bases: usize,
     pub rec_len: usize,
     pub ignored: bool,
+    /// the name of the read in the input file, up to the first whitespace. this is empty in
+    /// indexes created before read names were recorded
+    #[serde(default)]
+    pub name: String,
 }
 
 pub struct IndexWriter {
@@ -93,12 +97,15 @@ impl IndexWriter {
     /// * `wtr` - A mutable reference to a CSV writer.
     /// * `pos` - The position of the record in the file.
     /// * `file_len` - The bytes consumed by the record in the file (the _length_ on _file_)
+    /// * `ignored` - Whether the record was filtered out
+    /// * `header` - The original header of the record, before its identifier was extracted
     pub fn write_record(
         &mut self,
         rec: &Record,
         pos: usize,
         file_len: usize,
         ignored: bool,
+        header: &str,
     ) -> csv::Result<()> {
         self.wtr.serialize(IndexRecord {
             id: rec.id.clone(),
@@ -107,6 +114,11 @@ impl IndexWriter {
             n_bases: rec.len(),
             rec_len: file_len,
             ignored,
+            name: header
+                .split_whitespace()
+                .next()
+                .unwrap_or_default()
+                .to_string(),
         })
     }
 }
@@ -157,6 +169,14 @@ impl IndexReader {
         let (_, mut rdr) = self.create_reader()?;
         Ok(rdr.into_deserialize())
     }
+
+    /// Returns whether the index records the name of each read, which indexes created before
+    /// read names were recorded do not.
+    pub fn has_read_names(&self) -> Result<bool> {
+        let (_, mut rdr) = self.create_reader()?;
+        let headers = rdr.headers().context("Could not read the index header")?;
+        Ok(headers.iter().any(|h| h == "name"))
+    }
 }
 
 /// Iterates over lines in a FASTQ file, extracting barcodes using a regex
@@ -233,9 +253,9 @@ fn iter_lines_with_regex(
             })
         }
 
-        rec.id = identifier.to_string();
+        let header = std::mem::replace(&mut rec.id, identifier.to_string());
 
-        wtr.write_record(&rec, position, file_len, ignored)?;
+        wtr.write_record(&rec, position, file_len, ignored, &header)?;
         total_quality += rec.phred_quality_total();
         total_len += rec.len();
         wtr.metadata.matched_read_count += 1;
@@ -336,8 +356,8 @@ fn iter_lines_with_cluster_file(
         };
         wtr.metadata.matched_read_count += 1;
 
-        rec.id = identifier.clone();
-        wtr.write_record(&rec, position, file_len, ignored)?;
+        let header = std::mem::replace(&mut rec.id, identifier.clone());
+        wtr.write_record(&rec, position, file_len, ignored, &header)?;
 
         total_quality += rec.phred_quality_total();
         total_len += rec.len();
@@ -1,8 +1,20 @@
 use crate::duplicates::{DuplicateMap, RecordIdentifier, RecordPosition};
-use anyhow::{Context, Result};
+use crate::external::{ExternalGroups, ExternalSortOpts};
+use crate::fetch;
+use crate::orient::Strand;
+use crate::output::RecordWriter;
+use crate::partition::{PartitionWriter, Partitions};
+use crate::qc::ConsensusQc;
+use crate::select::GroupSelection;
+use crate::trim::TrimRegion;
+use anyhow::{bail, Context, Result};
+#[cfg(unix)]
+use memmap2::Advice;
+use memmap2::Mmap;
 use needletail::parser::SequenceRecord;
-use needletail::{parse_fastx_reader, parser::FastqReader, FastxReader};
-use std::collections::HashSet;
+use needletail::{parse_fastx_reader, FastxReader};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::collections::{HashSet, VecDeque};
 use std::fmt::Write as FmtWrite;
 // needed for write! to be implemented on Strings
 use crate::index::{IndexReader, IndexReaderRecords, IndexRecord};
@@ -10,6 +22,7 @@ use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::{BufReader, Read, Seek, SeekFrom, Write};
 use std::iter::Map;
+use std::path::PathBuf;
 use std::rc::Rc;
 use std::slice::Iter;
 
@@ -109,15 +122,24 @@ impl Record {
 
         // safe to unwrap because this never returns an error
         //   https://github.com/rust-lang/rust/blob/1.47.0/library/alloc/src/string.rs#L2414-L2427
-        // ">{} UG:i:{} BX:Z:{} UT:Z:{}_{}\n{}",
-        write!(self.id, " UT:Z:{read_type_label} UG:i:{umi_group}")
-            .expect("String writing should not error");
+        write!(self.id, " UT:Z:{read_type_label}").expect("String writing should not error");
+
+        // ignored reads are not part of any UMI group
+        if read_type != ReadType::Ignored {
+            write!(self.id, " UG:i:{umi_group}").expect("String writing should not error");
+        }
 
         // don't report the group average quality if the readtype is Original or Ignored
         if !matches!(read_type, ReadType::Original | ReadType::Ignored) {
             write!(self.id, " QL:f:{avg_qual:.2}").expect("String writing should not error");
         }
     }
+
+    /// Adds an extra `TAG:TYPE:VALUE` tag to the record identifier through an in-place modify,
+    /// after any metadata added by `add_metadata`.
+    pub fn add_tag(&mut self, tag: &str, value: impl std::fmt::Display) {
+        write!(self.id, " {tag}:{value}").expect("String writing should not error");
+    }
 }
 
 pub struct UMIGroup {
@@ -129,71 +151,206 @@ pub struct UMIGroup {
     pub records: Vec<Record>,
     /// The average PHRED quality of the UMI group
     pub avg_qual: f64,
-    /// Whether we should NOT consensus call this UMI group, because of quality/other issues
+    /// Whether we should NOT consensus call this UMI group, because of quality/other issues. An
+    /// ignored group holds a single read which was filtered out during indexing, and shares its
+    /// `index` with the next UMI group
     pub ignore: bool,
     pub consensus: Option<Record>,
+    /// The strand of each record relative to the consensus, if it was oriented before calling
+    pub strands: Vec<Option<Strand>>,
+    /// The region of each record which is kept after trimming, if reads were trimmed
+    pub trims: Vec<TrimRegion>,
+    /// Quality control statistics of the consensus, if requested
+    pub qc: Option<ConsensusQc>,
+    /// The multiple sequence alignment of the group as `(name, aligned sequence)` rows, ending
+    /// with the consensus. This is only filled in if set to `Some` before calling
+    pub msa: Option<Vec<(String, String)>>,
 }
 
-/// Retrieves a FASTQ record from a file at a specified position.
-///
-/// # Errors
-///
-/// This function will return an error if:
-/// * The file cannot be seeked to the specified position, where position is the start byte
-///   of the required read.
-/// * The FASTQ reader encounters an unexpected EOF.
-/// * The record ID, sequence, or quality scores cannot be converted from UTF-8.
-///
-/// # Example
-///
-/// ```
-/// use std::fs::File;
-/// use anyhow::Result;
-///
-/// let mut file = File::open("example.fastq")?;
-/// let record = get_read_at_position(&mut file, 12345)?;
-/// println!("Record ID: {}", record.id);
-/// ```
-pub fn get_record_from_position<R: Read + Seek + Send>(
-    // file: &mut File,
-    reader: &mut R,
-    // file_sequential: &mut dyn Read,
-    // file_random: &mut dyn Read,
-    pos: &RecordPosition,
-) -> anyhow::Result<Record> {
-    // go to the position of the record
-    reader
-        .seek(SeekFrom::Start(pos.pos as u64))
-        .with_context(|| format!("Unable to seek file at position {}", pos.pos))?;
-
-    // read the exact number of bytes
-    // let mut bytes = Vec::with_capacity(pos.length);
-    let mut bytes = vec![0; pos.length];
-    reader.read_exact(&mut bytes).with_context(|| {
-        format!(
-            "Could not read {} lines at position {}",
-            pos.length, pos.pos
-        )
-    })?;
+/// Where reads which were filtered out during indexing are written.
+pub enum IgnoredOutput {
+    /// ignored reads are not written
+    Skip,
+    /// ignored reads are written to the main output, in their input order
+    Inline,
+    /// ignored reads are written to a separate file
+    Separate(RecordWriter<Box<dyn Write + Send>>),
+}
 
-    // create a needletail 'reader' with the file at this location
-    let mut fq_reader = FastqReader::new(&bytes[..]);
+impl IgnoredOutput {
+    /// Returns true if ignored reads should be read from the input at all.
+    pub fn include(&self) -> bool {
+        !matches!(self, IgnoredOutput::Skip)
+    }
 
-    let rec = fq_reader.next().context("Unexpected EOF")??;
+    /// Finishes writing the separate file of ignored reads, if there is one.
+    pub fn finish(self) -> Result<()> {
+        match self {
+            IgnoredOutput::Separate(writer) => writer.finish(),
+            _ => Ok(()),
+        }
+    }
+}
 
-    Record::try_from(rec).context("Could not perform utf8 conversions")
+/// The groups of duplicate reads, which are either held in memory or streamed from disk.
+enum GroupLookup {
+    InMemory {
+        duplicates: DuplicateMap,
+        /// the index of the next group to be visited by random access
+        next: usize,
+    },
+    External {
+        groups: ExternalGroups,
+        /// the next group to be visited, which is peeked so that it can be matched against the
+        /// current position in the input file
+        next: Option<(RecordIdentifier, Vec<RecordPosition>)>,
+    },
 }
 
-pub struct UMIGroupCollection {
+impl GroupLookup {
+    /// Returns the group that starts with the read at `position`, or `None` if this read should be
+    /// skipped because it is a later member of a group which has already been visited.
+    fn group_starting_at(
+        &mut self,
+        position: usize,
+        visited_reads: &mut HashSet<usize>,
+    ) -> Result<Option<Vec<RecordPosition>>> {
+        match self {
+            GroupLookup::InMemory { duplicates, .. } => {
+                if visited_reads.contains(&position) {
+                    return Ok(None);
+                }
+
+                // reads of groups which were not selected are not in the map
+                let Some(group) = duplicates.records_by_pos(&position) else {
+                    return Ok(None);
+                };
+                let group = group.clone();
+
+                // note: we don't need to add the first read, since traversal is in order
+                visited_reads.extend(group.iter().skip(1).map(|p| p.pos));
+                Ok(Some(group))
+            }
+            GroupLookup::External { groups, next } => {
+                // groups are in order of their first read, so any read which does not start the
+                // next group must belong to a group that has already been visited
+                let starts_next = matches!(next, Some((_, group)) if group[0].pos == position);
+                if !starts_next {
+                    return Ok(None);
+                }
+
+                let (_, group) =
+                    std::mem::replace(next, groups.next_group()?).expect("Group should exist");
+                Ok(Some(group))
+            }
+        }
+    }
+
+    /// Returns the next group in order of its first read, for iterating over groups by random
+    /// access rather than by streaming the input file.
+    fn next_group(&mut self) -> Result<Option<(RecordIdentifier, Vec<RecordPosition>)>> {
+        match self {
+            GroupLookup::InMemory { duplicates, next } => {
+                let group = duplicates
+                    .by_id
+                    .get_index(*next)
+                    .map(|(id, group)| (id.clone(), group.clone()));
+                *next += 1;
+                Ok(group)
+            }
+            GroupLookup::External { next, groups } => {
+                Ok(std::mem::replace(next, groups.next_group()?))
+            }
+        }
+    }
+}
+
+/// A sequential reader over the input file, paired with the corresponding index records.
+struct SequentialReader {
     seq_parser: Box<dyn FastxReader>,
-    rnd_reader: File,
-    index: IndexReader,
-    duplicates: DuplicateMap,
     records: IndexReaderRecords,
 }
 
+impl SequentialReader {
+    /// Retrieves the next record from the sequence parser and the corresponding index record.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// * The sequence parser encounters an error while reading the next record.
+    /// * The index reader encounters an error while reading the next index item.
+    fn next_record(&mut self) -> Result<Option<(IndexRecord, SequenceRecord<'_>)>> {
+        let Some(rec) = self.seq_parser.next() else {
+            return Ok(None);
+        };
+        let rec = rec?;
+        let idx = self
+            .records
+            .next()
+            .context("No corresponding index record")??;
+
+        Ok(Some((idx, rec)))
+    }
+}
+
+pub struct UMIGroupCollection {
+    sequential: SequentialReader,
+    rnd_reader: File,
+    groups: GroupLookup,
+    /// the groups which are returned. if only some groups are selected, these are read by random
+    /// access rather than by streaming the whole input file
+    selection: GroupSelection,
+    /// if set, the number of partitions which reads are split into by a sequential pass over the
+    /// input, instead of reading the other reads of each group by random access
+    partition_count: Option<usize>,
+    /// the directory which partitions are written to, or `None` for the system temporary directory
+    partition_dir: Option<PathBuf>,
+    partitions: Option<Partitions>,
+    /// if set, the pool on which batches of records are read by random access in parallel
+    io_pool: Option<ThreadPool>,
+    /// if set, a memory mapping of the input file, which records are read from by random access
+    mmap: Option<Mmap>,
+}
+
 impl UMIGroupCollection {
-    pub fn new(mut index: IndexReader, input: &str) -> Result<Self> {
+    /// Creates a collection which groups duplicate reads in memory. Only the selected groups are
+    /// returned, and these keep the index which they have when every group is selected.
+    pub fn new(mut index: IndexReader, input: &str, selection: GroupSelection) -> Result<Self> {
+        let (duplicates, _) = index.get_duplicates()?;
+        duplicates.log_selected(&selection);
+
+        let groups = GroupLookup::InMemory {
+            duplicates,
+            next: 0,
+        };
+        Self::from_groups(index, input, groups, selection)
+    }
+
+    /// Creates a collection which groups duplicate reads on disk, using a bounded amount of
+    /// memory. See `IndexReader::get_duplicates_external` for more.
+    pub fn new_external(
+        mut index: IndexReader,
+        input: &str,
+        opts: &ExternalSortOpts,
+        selection: GroupSelection,
+    ) -> Result<Self> {
+        let (mut groups, _) = index.get_duplicates_external(opts)?;
+
+        let next = groups.next_group()?;
+        Self::from_groups(
+            index,
+            input,
+            GroupLookup::External { groups, next },
+            selection,
+        )
+    }
+
+    fn from_groups(
+        mut index: IndexReader,
+        input: &str,
+        groups: GroupLookup,
+        selection: GroupSelection,
+    ) -> Result<Self> {
         let file = File::open(input).with_context(|| format!("Unable to open file {input}"))?;
 
         // create a sequential reader with a buffer size of BUF_CAPACITY
@@ -207,39 +364,111 @@ impl UMIGroupCollection {
         let mut rnd_reader =
             File::open(input).with_context(|| format!("Unable to open file {input}"))?;
 
-        let (duplicates, _) = index.get_duplicates()?;
         let records = index.index_records()?;
 
         Ok(UMIGroupCollection {
-            seq_parser,
+            sequential: SequentialReader {
+                seq_parser,
+                records,
+            },
             rnd_reader,
-            index,
-            duplicates,
-            records,
+            groups,
+            selection,
+            partition_count: None,
+            partition_dir: None,
+            partitions: None,
+            io_pool: None,
+            mmap: None,
         })
     }
 
-    /// Retrieves the next record from the sequence parser and the corresponding index record.
-    ///
-    /// # Errors
-    ///
-    /// This function will return an error if:
-    /// * The sequence parser encounters an error while reading the next record.
-    /// * The index reader encounters an error while reading the next index item.
-    pub fn next_record(&mut self) -> Result<Option<(IndexRecord, SequenceRecord)>> {
-        let Some(rec) = self.seq_parser.next() else {
-            return Ok(None);
+    /// Reads groups by splitting the input into `n` partitions with one sequential pass, rather
+    /// than by random access. The partitions are written to `dir` if given, or otherwise to the
+    /// system temporary directory. See `ReadStrategy::Partition` for more.
+    pub fn read_by_partition(&mut self, n: usize, dir: Option<PathBuf>) {
+        self.partition_count = Some(n);
+        self.partition_dir = dir;
+    }
+
+    /// Reads each batch of records by random access from `n` threads at once, which can be faster
+    /// on network or parallel filesystems. The threads share the same file descriptor.
+    pub fn set_io_threads(&mut self, n: usize) -> Result<()> {
+        self.io_pool = match n {
+            0 | 1 => None,
+            n => Some(
+                ThreadPoolBuilder::new()
+                    .num_threads(n)
+                    .build()
+                    .context("Could not create I/O thread pool")?,
+            ),
         };
-        let rec = rec?;
-        let idx = self
-            .records
-            .next()
-            .context("No corresponding index record")??;
+        Ok(())
+    }
 
-        Ok(Some((idx, rec)))
+    /// Reads records by random access from a memory mapping of the input file, parsing them in
+    /// place rather than copying them into a new buffer for every read. If the input cannot be
+    /// mapped, such as when it is a pipe, a warning is logged and records are read as usual.
+    pub fn use_mmap(&mut self) {
+        let is_file = self.rnd_reader.metadata().is_ok_and(|m| m.is_file());
+        if !is_file {
+            warn!("The input is not a regular file, so it will not be memory mapped");
+            return;
+        }
+
+        // SAFETY: the input must not be modified while it is mapped, just as it must not be
+        // modified between indexing and reading it
+        match unsafe { Mmap::map(&self.rnd_reader) } {
+            Ok(map) => {
+                #[cfg(unix)]
+                if let Err(e) = map.advise(Advice::Random) {
+                    warn!("Could not advise the kernel of random access: {e}");
+                }
+                self.mmap = Some(map);
+            }
+            Err(e) => warn!("Could not memory map the input, so it will be read as usual: {e}"),
+        }
+    }
+
+    /// Splits every read into partitions by its group, in one sequential pass over the input.
+    /// Reads of groups which were not selected are skipped.
+    fn write_partitions(&mut self, n: usize, include_ignored: bool) -> Result<Partitions> {
+        let GroupLookup::InMemory { duplicates, .. } = &self.groups else {
+            bail!("Reads can only be partitioned when duplicates are grouped in memory");
+        };
+        info!("Splitting reads into {n} partitions...");
+
+        let mut writer = PartitionWriter::new(n, self.partition_dir.as_deref())?;
+        while let Some((idx, rec)) = self.sequential.next_record()? {
+            let mut raw = rec.all().to_vec();
+            raw.push(b'\n');
+
+            if idx.ignored {
+                if include_ignored {
+                    writer.add(None, &idx.id, &raw)?;
+                }
+                continue;
+            }
+
+            let group = duplicates
+                .pos_to_id
+                .get(&idx.pos)
+                .and_then(|id| duplicates.by_id.get_full(id));
+            if let Some((group, id, positions)) = group {
+                if self.selection.selects(id, positions.len()) {
+                    writer.add(Some(group), &idx.id, &raw)?;
+                }
+            }
+        }
+
+        writer.finish()
     }
 
     pub fn get_rec_random(&mut self, pos: &RecordPosition) -> Result<Record> {
+        // records are parsed in place when the input is memory mapped
+        if let Some(map) = &self.mmap {
+            return fetch::parse_record(fetch::mapped_record(map, pos)?);
+        }
+
         self.rnd_reader
             .seek(SeekFrom::Start(pos.pos as u64))
             .with_context(|| format!("Unable to seek file at position {}", pos.pos))?;
@@ -253,12 +482,7 @@ impl UMIGroupCollection {
             )
         })?;
 
-        // create a needletail 'reader' with the file at this location
-        let mut fq_reader = FastqReader::new(&bytes[..]);
-
-        let rec = fq_reader.next().context("Unexpected EOF")??;
-
-        Record::try_from(rec).context("Could not perform utf8 conversions")
+        fetch::parse_record(&bytes)
     }
 
     /// Creates a _streaming_ iterator over UMI groups in the collection.
@@ -268,16 +492,27 @@ impl UMIGroupCollection {
     /// # Arguments
     ///
     /// * `duplicates_only` - A boolean indicating whether to process only duplicate reads.
+    /// * `include_ignored` - A boolean indicating whether to yield reads which were filtered out
+    ///   during indexing, each as its own group with `ignore` set, in their input order.
     ///
     /// # Returns
     ///
     /// This function returns an iterator over `UMIGroupCollectionIter` which returns `UMIGroup`.
-    pub fn stream_iter(&mut self, duplicates_only: bool) -> UMIGroupCollectionIter {
+    pub fn stream_iter(
+        &mut self,
+        duplicates_only: bool,
+        include_ignored: bool,
+    ) -> UMIGroupCollectionIter<'_> {
         UMIGroupCollectionIter {
             collection: self,
             visited_reads: HashSet::new(),
             duplicates_only,
+            include_ignored,
             current_idx: 0,
+            skip: 0,
+            ordinals: Vec::new(),
+            batch_size: 1,
+            queue: VecDeque::new(),
         }
     }
 }
@@ -286,7 +521,15 @@ pub struct UMIGroupCollectionIter<'a> {
     collection: &'a mut UMIGroupCollection,
     visited_reads: HashSet<usize>,
     duplicates_only: bool,
+    include_ignored: bool,
     current_idx: usize,
+    skip: usize,
+    /// when reading by partition, the index of each group as it would be numbered when streaming
+    ordinals: Vec<Option<usize>>,
+    /// the number of groups whose records are read at once
+    batch_size: usize,
+    /// groups which have been read, but not yet returned
+    queue: VecDeque<UMIGroup>,
 }
 
 impl UMIGroupCollectionIter<'_> {
@@ -306,57 +549,310 @@ impl UMIGroupCollectionIter<'_> {
     /// * There are issues reading the read at at the specified position. See the documentation for
     ///   `get_read_at_position` for more.
     pub fn next(&mut self) -> Result<Option<UMIGroup>> {
-        let Some((idx, rec)) = self.collection.next_record()? else {
+        if let Some(n) = self.collection.partition_count {
+            return self.next_partitioned(n);
+        }
+
+        if self.batch_size > 1 {
+            if self.queue.is_empty() {
+                self.fill_queue()?;
+            }
+            return Ok(self.queue.pop_front());
+        }
+
+        let Some(pending) = self.next_pending()? else {
             return Ok(None);
         };
-        // note: we don't need to add this to visited_reads, since traversal is in order
-        let position = rec.position().byte() as usize;
+        let records = pending
+            .missing
+            .iter()
+            .map(|pos| self.collection.get_rec_random(pos))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Some(pending.finish(records)))
+    }
 
-        // if this is marked to ignore or we have already visited this, we can skip
-        if self.visited_reads.contains(&position) || idx.ignored {
-            return self.next();
+    /// Reads the records of the next `batch_size` groups at once, and adds the groups to the
+    /// queue. The records which are not read by streaming are sorted and read in coalesced ranges
+    /// rather than one at a time; see `fetch::read_records` for more.
+    fn fill_queue(&mut self) -> Result<()> {
+        let mut pending = Vec::with_capacity(self.batch_size);
+        while pending.len() < self.batch_size {
+            match self.next_pending()? {
+                Some(group) => pending.push(group),
+                None => break,
+            }
         }
 
-        let rec = Record::try_from(rec).context("Could not perform utf8 conversions")?;
-        // get the corresponding entry in duplicates
-        let id = RecordIdentifier::from_string(&idx.id);
-        let group = self
-            .collection
-            .duplicates
-            .records_by_pos(&position)
-            .context("Could not find")?
-            .clone();
-
-        // skip over group sizes which are more than 1
-        let group_size = group.len();
-        if self.duplicates_only && group_size == 1 {
-            return self.next();
+        let positions = pending
+            .iter()
+            .flat_map(|group| group.missing.iter().copied())
+            .collect();
+        // records in a memory mapping are parsed in place, so there is nothing to gain by
+        // coalescing them
+        if self.collection.mmap.is_some() {
+            for group in pending {
+                let records = group
+                    .missing
+                    .iter()
+                    .map(|pos| self.collection.get_rec_random(pos))
+                    .collect::<Result<Vec<_>>>()?;
+                self.queue.push_back(group.finish(records));
+            }
+            return Ok(());
         }
 
-        let mut records = Vec::with_capacity(group_size);
-        records.push(rec);
+        let mut fetched = fetch::read_records(
+            &self.collection.rnd_reader,
+            positions,
+            self.collection.io_pool.as_ref(),
+        )?;
+
+        for group in pending {
+            let records = group
+                .missing
+                .iter()
+                .map(|pos| {
+                    fetched
+                        .remove(&pos.pos)
+                        .with_context(|| format!("Could not read record at position {}", pos.pos))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            self.queue.push_back(group.finish(records));
+        }
+        Ok(())
+    }
 
-        // get all the other records as well - skip the first one, that's `rec`
-        for pos in group.iter().skip(1) {
-            self.visited_reads.insert(pos.pos);
+    /// Returns the next group, without reading the records which are not read by streaming.
+    fn next_pending(&mut self) -> Result<Option<PendingGroup>> {
+        if !self.collection.selection.is_all() {
+            return self.next_random();
+        }
 
-            let rec = self.collection.get_rec_random(pos)?;
-            records.push(rec)
+        loop {
+            let Some((idx, rec)) = self.collection.sequential.next_record()? else {
+                return Ok(None);
+            };
+            let position = rec.position().byte() as usize;
+
+            // if this is marked to ignore, we can skip, or pass it through by itself
+            if idx.ignored {
+                if !self.include_ignored {
+                    continue;
+                }
+                if self.skip > 0 {
+                    self.skip -= 1;
+                    continue;
+                }
+
+                let rec = Record::try_from(rec).context("Could not perform utf8 conversions")?;
+                return Ok(Some(PendingGroup::new(
+                    RecordIdentifier::from_string(&idx.id),
+                    self.current_idx,
+                    vec![rec],
+                    Vec::new(),
+                    true,
+                )));
+            }
+
+            // get the corresponding group, skipping this read if we have already visited it
+            let Some(group) = self
+                .collection
+                .groups
+                .group_starting_at(position, &mut self.visited_reads)?
+            else {
+                continue;
+            };
+
+            // skip over group sizes which are more than 1
+            let group_size = group.len();
+            if self.duplicates_only && group_size == 1 {
+                continue;
+            }
+
+            // skipped groups keep their index, so that the remaining groups are numbered as usual
+            if self.skip > 0 {
+                self.skip -= 1;
+                self.current_idx += 1;
+                continue;
+            }
+
+            let rec = Record::try_from(rec).context("Could not perform utf8 conversions")?;
+            let id = RecordIdentifier::from_string(&idx.id);
+
+            let mut records = Vec::with_capacity(group_size);
+            records.push(rec);
+
+            // the other records are read later - skip the first one, that's `rec`
+            let missing = group.iter().skip(1).copied().collect();
+
+            let pending = PendingGroup::new(id, self.current_idx, records, missing, false);
+            self.current_idx += 1;
+
+            return Ok(Some(pending));
         }
+    }
 
-        let avg_qual =
-            records.iter().map(|r| r.phred_quality_avg()).sum::<f64>() / (records.len() as f64);
+    /// Returns the next group, whose records are all to be read by random access. Reads which are
+    /// not in any group, including those filtered out during indexing, are never read.
+    fn next_random(&mut self) -> Result<Option<PendingGroup>> {
+        loop {
+            let Some((id, group)) = self.collection.groups.next_group()? else {
+                return Ok(None);
+            };
+
+            if self.duplicates_only && group.len() == 1 {
+                continue;
+            }
+            // unselected groups are never read, but keep their place in the numbering
+            if !self.collection.selection.selects(&id, group.len()) {
+                self.current_idx += 1;
+                continue;
+            }
+            if self.skip > 0 {
+                self.skip -= 1;
+                self.current_idx += 1;
+                continue;
+            }
+
+            let records = Vec::with_capacity(group.len());
+            let pending = PendingGroup::new(id, self.current_idx, records, group, false);
+            self.current_idx += 1;
+
+            return Ok(Some(pending));
+        }
+    }
 
-        let umigroup = UMIGroup {
-            id,
-            index: self.current_idx,
-            records,
-            avg_qual,
-            ignore: false,
-            consensus: None,
-        };
-        self.current_idx += 1;
+    /// Returns the next group from the partitions of the input, splitting the input into `n`
+    /// partitions first if this has not yet been done. Groups keep the index which they would have
+    /// when streaming, but are returned in the order of their partitions.
+    fn next_partitioned(&mut self, n: usize) -> Result<Option<UMIGroup>> {
+        if self.collection.partitions.is_none() {
+            let GroupLookup::InMemory { duplicates, .. } = &self.collection.groups else {
+                bail!("Reads can only be partitioned when duplicates are grouped in memory");
+            };
+
+            // groups of a single read are not numbered when only duplicates are returned, while
+            // unselected groups are numbered but not returned
+            let selection = &self.collection.selection;
+            let mut count = 0;
+            self.ordinals = duplicates
+                .by_id
+                .iter()
+                .map(|(id, group)| {
+                    if self.duplicates_only && group.len() == 1 {
+                        return None;
+                    }
+                    count += 1;
+                    selection.selects(id, group.len()).then_some(count - 1)
+                })
+                .collect();
+            self.current_idx = count;
+
+            let partitions = self.collection.write_partitions(n, self.include_ignored)?;
+            self.collection.partitions = Some(partitions);
+        }
+        let partitions = self.collection.partitions.as_mut().expect("Should exist");
+
+        loop {
+            let Some(group) = partitions.next_group()? else {
+                return Ok(None);
+            };
+
+            // reads which were filtered out share their index with the group after the last
+            let index = match group.index {
+                Some(i) => match self.ordinals[i] {
+                    Some(index) => index,
+                    None => continue,
+                },
+                None => self.current_idx,
+            };
+            if self.skip > 0 {
+                self.skip -= 1;
+                continue;
+            }
+
+            let avg_qual = group
+                .records
+                .iter()
+                .map(|r| r.phred_quality_avg())
+                .sum::<f64>()
+                / (group.records.len() as f64);
+
+            return Ok(Some(UMIGroup {
+                id: group.id,
+                index,
+                records: group.records,
+                avg_qual,
+                ignore: group.index.is_none(),
+                consensus: None,
+                strands: Vec::new(),
+                trims: Vec::new(),
+                qc: None,
+                msa: None,
+            }));
+        }
+    }
+
+    /// Skips the next `n` groups which would be returned by `next`, without reading their
+    /// records. Skipped groups still count towards the index of later groups.
+    pub fn skip_groups(&mut self, n: usize) {
+        self.skip += n;
+    }
 
-        Ok(Some(umigroup))
+    /// Reads the records of `n` groups at a time, rather than reading the records of each group as
+    /// it is returned. This has no effect when reading by partition.
+    pub fn batch(&mut self, n: usize) {
+        self.batch_size = n;
+    }
+}
+
+/// A group whose records have not all been read yet.
+///
+/// # Fields
+///
+/// * `group` - The group, holding the records which have been read
+/// * `missing` - The positions of the records which are still to be read, in group order
+struct PendingGroup {
+    group: UMIGroup,
+    missing: Vec<RecordPosition>,
+}
+
+impl PendingGroup {
+    fn new(
+        id: RecordIdentifier,
+        index: usize,
+        records: Vec<Record>,
+        missing: Vec<RecordPosition>,
+        ignore: bool,
+    ) -> Self {
+        PendingGroup {
+            group: UMIGroup {
+                id,
+                index,
+                records,
+                avg_qual: 0.0,
+                ignore,
+                consensus: None,
+                strands: Vec::new(),
+                trims: Vec::new(),
+                qc: None,
+                msa: None,
+            },
+            missing,
+        }
+    }
+
+    /// Completes the group with its missing records, which must be in the same order as
+    /// `missing`.
+    fn finish(self, records: Vec<Record>) -> UMIGroup {
+        let mut group = self.group;
+        group.records.extend(records);
+        group.avg_qual = group
+            .records
+            .iter()
+            .map(|r| r.phred_quality_avg())
+            .sum::<f64>()
+            / (group.records.len() as f64);
+        group
     }
 }
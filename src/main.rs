@@ -5,27 +5,50 @@ extern crate env_logger;
 #[macro_use]
 extern crate log;
 use std::{
-    fs::File,
-    io::{prelude::*, stdout, BufWriter},
-    path::Path,
+    fs::{File, OpenOptions},
+    io::{prelude::*, stdout, BufWriter, SeekFrom},
+    path::{Path, PathBuf},
 };
 
-use anyhow::Result;
+use anyhow::{bail, ensure, Context, Result};
 use clap::Parser;
 
+mod align;
+mod assign;
+mod bgzf;
 mod call;
+mod cells;
+mod checkpoint;
 mod cli;
 mod duplicates;
+mod external;
+mod fetch;
 mod file;
 mod filter;
 mod group;
 mod index;
 mod io;
+mod msa;
+mod multiqc;
+mod orient;
+mod output;
+mod partition;
 mod preset;
+mod qc;
+mod select;
+mod split;
 mod summary;
+mod trim;
 
-use crate::io::UMIGroupCollection;
-use cli::{Cli, Commands};
+use crate::checkpoint::{Checkpointer, Fingerprint};
+use crate::external::ExternalSortOpts;
+use crate::io::{IgnoredOutput, UMIGroupCollection};
+use crate::output::{OutputCompression, OutputFormat, OutputWriter, RecordWriter};
+use crate::partition::ReadStrategy;
+use crate::select::GroupSelection;
+use crate::split::SplitWriter;
+use crate::summary::SummaryFormat;
+use cli::{Cli, Commands, GroupingOpts, IgnoredArgs, SelectionArgs, SplitArgs};
 
 /// Creates a `BufWriter` for the given output option. This allows for an output file to be passed
 /// or otherwise will default to using standard output.
@@ -40,7 +63,7 @@ use cli::{Cli, Commands};
 /// # Returns
 ///
 /// A `Result` containing a `BufWriter` that implements `Write`.
-fn get_writer(output: &Option<String>) -> Result<impl Write> {
+fn get_writer(output: &Option<String>) -> Result<BufWriter<Box<dyn Write + Send>>> {
     // get output as a BufWriter - equal to stdout if None
     let writer = BufWriter::new(match output {
         Some(ref x) => {
@@ -52,6 +75,133 @@ fn get_writer(output: &Option<String>) -> Result<impl Write> {
     Ok(writer)
 }
 
+/// Creates the writer for the reads output by `call` and `group`, which is either a single file
+/// (or standard output), or a directory of files if the output is split.
+///
+/// # Arguments
+///
+/// * `output` - The output file or directory, or `None` for standard output.
+/// * `format` - The format of the output.
+/// * `compress` - The compression given on the command line, if any.
+/// * `split` - How the output is split, if at all.
+fn get_output_writer(
+    output: &Option<String>,
+    format: OutputFormat,
+    compress: Option<OutputCompression>,
+    split: &SplitArgs,
+) -> Result<OutputWriter<BufWriter<Box<dyn Write + Send>>>> {
+    if split.split_by.is_none() {
+        let compression = OutputCompression::infer(compress, output);
+        let writer = RecordWriter::new(get_writer(output)?, format, compression)?;
+        return Ok(OutputWriter::Single(writer));
+    }
+
+    let Some(dir) = output else {
+        bail!("--split-by requires an output directory, given with -o");
+    };
+    let compression = compress.unwrap_or(OutputCompression::None);
+    let writer = SplitWriter::new(dir, format, compression, split.max_open_files)?;
+    Ok(OutputWriter::Split(writer))
+}
+
+/// Reopens an output file to continue an interrupted run, discarding anything written after the
+/// given offset.
+///
+/// # Arguments
+///
+/// * `output` - The path to the output file.
+/// * `offset` - The length of the output as of the checkpoint being resumed from.
+fn get_resumed_writer(output: &str, offset: u64) -> Result<BufWriter<Box<dyn Write + Send>>> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(output)
+        .with_context(|| format!("Could not open {output} to resume"))?;
+
+    let len = file.metadata()?.len();
+    ensure!(
+        len >= offset,
+        "{output} is shorter than its checkpoint ({len} < {offset} bytes), so cannot be resumed"
+    );
+
+    file.set_len(offset)?;
+    file.seek(SeekFrom::End(0))?;
+    Ok(BufWriter::new(Box::new(file) as Box<dyn Write + Send>))
+}
+
+/// Creates the destination for reads which were filtered out during indexing, from the
+/// command line options. A separate file of ignored reads is written in the given format, and
+/// compressed as given or as implied by its extension.
+fn get_ignored_output(
+    ignored: &IgnoredArgs,
+    format: OutputFormat,
+    compress: Option<OutputCompression>,
+) -> Result<IgnoredOutput> {
+    if let Some(path) = &ignored.ignored_output {
+        let file = File::create(Path::new(path))
+            .with_context(|| format!("Could not create ignored read file {path}"))?;
+        let writer: Box<dyn Write + Send> = Box::new(BufWriter::new(file));
+        let compression = OutputCompression::infer(compress, &ignored.ignored_output);
+        Ok(IgnoredOutput::Separate(RecordWriter::new(
+            writer,
+            format,
+            compression,
+        )?))
+    } else if ignored.include_ignored {
+        Ok(IgnoredOutput::Inline)
+    } else {
+        Ok(IgnoredOutput::Skip)
+    }
+}
+
+/// Returns the groups chosen by the command line options. Reads which were filtered out during
+/// indexing do not belong to any group, so cannot be passed through when only some groups are
+/// selected.
+fn get_selection(selection: &SelectionArgs, ignored: &IgnoredArgs) -> Result<GroupSelection> {
+    let selection = selection.selection()?;
+    ensure!(
+        selection.is_all() || !(ignored.include_ignored || ignored.ignored_output.is_some()),
+        "--include-ignored and --ignored-output cannot be used with --barcodes, \
+         --select-min-size or --select-max-size"
+    );
+    Ok(selection)
+}
+
+/// Creates a `UMIGroupCollection` from an index and its input file, grouping duplicates either in
+/// memory or on disk depending on the given `GroupingOpts`.
+fn get_collection(
+    index: &str,
+    input: &str,
+    grouping: &GroupingOpts,
+    selection: GroupSelection,
+) -> Result<UMIGroupCollection> {
+    let index = index::IndexReader::from_path(index)?;
+
+    let mut collection = match grouping.tmp_dir {
+        // when reading by partition, the temporary directory holds the partitions instead
+        Some(ref tmp_dir) if grouping.read_by != ReadStrategy::Partition => {
+            let opts = ExternalSortOpts {
+                tmp_dir: tmp_dir.into(),
+                memory_limit: grouping.memory_limit * 1024usize.pow(2),
+            };
+            UMIGroupCollection::new_external(index, input, &opts, selection)?
+        }
+        _ => {
+            let mut collection = UMIGroupCollection::new(index, input, selection)?;
+            if grouping.read_by == ReadStrategy::Partition {
+                let dir = grouping.tmp_dir.as_ref().map(PathBuf::from);
+                collection.read_by_partition(grouping.partitions, dir);
+            }
+            collection
+        }
+    };
+    collection.set_io_threads(grouping.io_threads)?;
+    if grouping.mmap {
+        collection.use_mmap();
+    }
+
+    Ok(collection)
+}
+
 fn try_main() -> Result<()> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
         .format_target(false)
@@ -59,11 +209,33 @@ fn try_main() -> Result<()> {
 
     let cli = Cli::parse();
 
-    println!("nailpolish v{}", cli::VERSION);
+    info!("nailpolish v{}", cli::VERSION);
 
     match &cli.command {
-        Commands::Summary { index, output } => {
-            summary::summarize(index, output)?;
+        Commands::Summary {
+            index,
+            sample_name,
+            output,
+            report,
+            cells,
+            extra_depth,
+            multiqc,
+        } => {
+            let output = match report.format {
+                SummaryFormat::Html if !report.dump_context => {
+                    Some(output.clone().unwrap_or("summary.html".into()))
+                }
+                _ => output.clone(),
+            };
+            summary::summarize(
+                index,
+                sample_name,
+                get_writer(&output)?,
+                report,
+                cells,
+                *extra_depth,
+                multiqc,
+            )?;
         }
         Commands::Index {
             file,
@@ -107,21 +279,131 @@ fn try_main() -> Result<()> {
             index,
             input,
             output,
+            output_format,
+            compress,
+            split,
             threads,
             duplicates_only,
             report_original_reads,
+            mode,
+            representative_by,
+            orient,
+            primer,
+            max_group_size,
+            downsample_by,
+            seed,
+            qc,
+            checkpoint,
+            resume,
+            msa,
+            alignment,
+            trim,
+            ignored,
+            selection,
+            grouping,
         } => {
-            let index = index::IndexReader::from_path(index)?;
-            let mut collection = UMIGroupCollection::new(index, input)?;
-            let mut writer = get_writer(output)?;
+            let selection = get_selection(selection, ignored)?;
+            ensure!(
+                *orient != orient::OrientMethod::Primer || primer.is_some(),
+                "--orient primer requires a --primer sequence"
+            );
+
+            ensure!(
+                qc.is_none() || *mode == call::CallMode::Consensus,
+                "--qc requires --mode consensus"
+            );
+            ensure!(
+                msa.msa_dir.is_none() || *mode == call::CallMode::Consensus,
+                "--msa-dir requires --mode consensus"
+            );
+
+            // runs can only be resumed if the output can be truncated to a checkpoint, and every
+            // other file written can be recreated from scratch. checkpoints sync the output to
+            // disk, so are only saved when asked for
+            let checkpointing = *checkpoint || *resume;
+            let compression = OutputCompression::infer(*compress, output);
+            let resumable = output.is_some()
+                && split.split_by.is_none()
+                && compression == OutputCompression::None
+                && *output_format != OutputFormat::Ubam
+                && ignored.ignored_output.is_none()
+                && qc.is_none()
+                && msa.msa_dir.is_none();
+            ensure!(
+                resumable || !checkpointing,
+                "--checkpoint and --resume require an uncompressed --output in FASTQ, FASTA or \
+                 SAM format, and cannot be used with --ignored-output, --qc or --msa-dir"
+            );
+            let opts = call::ConsensusOpts {
+                orient: orient::OrientOpts {
+                    method: *orient,
+                    primer: primer.clone(),
+                },
+                mode: *mode,
+                representative_by: *representative_by,
+                max_group_size: *max_group_size,
+                downsample_by: *downsample_by,
+                seed: *seed,
+                alignment: alignment.params(),
+                trim: trim.opts()?,
+                qc: qc.clone(),
+                msa: msa.opts(*seed)?,
+            };
+            opts.alignment.validate()?;
+
+            let checkpointer = match output {
+                Some(output) if checkpointing => {
+                    let fingerprint = Fingerprint::new(input, index, &opts)?;
+                    Some(Checkpointer::new(output, *resume, fingerprint)?)
+                }
+                _ => None,
+            };
+
+            // record the parameters used next to the output, so that the run can be reproduced
+            info!("Using alignment parameters {:?}", opts.alignment);
+            if let Some(output) = output {
+                let params_path = format!("{output}.params.json");
+                call::write_params(&params_path, &opts)?;
+                info!("Wrote run parameters to {params_path}");
+            }
+
+            // the thread pool is shared by consensus calling and output compression, so it
+            // must be configured before any output is written
+            info!("Creating thread pool with {threads} threads");
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(*threads)
+                .build_global()?;
+
+            let mut ignored = get_ignored_output(ignored, *output_format, *compress)?;
+            let mut collection = get_collection(index, input, grouping, selection)?;
+
+            let resume_from = checkpointer.as_ref().map(|c| c.resume_from());
+            let mut writer = match (output, resume_from) {
+                (Some(output), Some(cp)) if cp.offset > 0 => {
+                    OutputWriter::Single(RecordWriter::append(
+                        get_resumed_writer(output, cp.offset)?,
+                        *output_format,
+                        compression,
+                        cp.offset,
+                    )?)
+                }
+                _ => get_output_writer(output, *output_format, *compress, split)?,
+            };
 
             call::consensus(
                 &mut collection,
                 &mut writer,
-                *threads,
                 *duplicates_only,
                 *report_original_reads,
+                &opts,
+                &mut ignored,
+                checkpointer.as_ref(),
             )?;
+            writer.finish()?;
+            ignored.finish()?;
+            if let Some(checkpointer) = checkpointer {
+                checkpointer.finish()?;
+            }
 
             info!("Completed successfully.")
         }
@@ -129,13 +411,71 @@ fn try_main() -> Result<()> {
             index,
             input,
             output,
+            output_format,
+            compress,
+            split,
+            trim,
+            ignored,
+            selection,
+            grouping,
+            shell,
+            threads,
+            command,
         } => {
-            let index = index::IndexReader::from_path(index)?;
-            let mut collection = UMIGroupCollection::new(index, input)?;
+            let selection = get_selection(selection, ignored)?;
+            let trim = trim.opts()?;
+
+            // the output of a command is written as is, so cannot be compressed or split
+            let command = (!command.is_empty()).then(|| group::GroupCommand {
+                shell: shell.clone(),
+                command: group::shell_command(command),
+                threads: *threads,
+            });
+            ensure!(
+                command.is_none()
+                    || (split.split_by.is_none()
+                        && OutputCompression::infer(*compress, output) == OutputCompression::None),
+                "The output of a command cannot be split or compressed"
+            );
 
-            let mut writer = get_writer(output)?;
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(*threads)
+                .build_global()?;
+
+            let mut ignored = get_ignored_output(ignored, *output_format, *compress)?;
+            let mut collection = get_collection(index, input, grouping, selection)?;
+
+            match command {
+                Some(command) => {
+                    let mut writer = get_writer(output)?;
+                    group::group_command(
+                        &mut collection,
+                        &mut writer,
+                        *output_format,
+                        &command,
+                        &trim,
+                        &mut ignored,
+                    )?;
+                }
+                None => {
+                    let mut writer = get_output_writer(output, *output_format, *compress, split)?;
+                    group::group(&mut collection, &mut writer, &trim, &mut ignored)?;
+                    writer.finish()?;
+                }
+            }
+            ignored.finish()?;
+
+            info!("Completed successfully.")
+        }
+        Commands::Assign {
+            index,
+            output,
+            selection,
+        } => {
+            let selection = selection.selection()?;
+            let mut index = index::IndexReader::from_path(index)?;
 
-            group::group(&mut collection, &mut writer)?;
+            assign::write_assignments(&mut index, get_writer(output)?, &selection)?;
 
             info!("Completed successfully.")
         }
@@ -0,0 +1,155 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+
+use crate::duplicates::RecordIdentifier;
+use crate::io::UMIGroup;
+
+/// Options which choose the groups whose multiple sequence alignment is written.
+///
+/// # Fields
+///
+/// * `dir` - The directory which each alignment is written to
+/// * `groups` - The indices of groups to write
+/// * `ids` - The identifiers of groups to write
+/// * `sample` - If set, the number of groups with more than one read to choose at random
+/// * `seed` - The seed used for random sampling
+#[derive(Clone, Debug, Serialize)]
+pub struct MsaOpts {
+    pub dir: String,
+    pub groups: Vec<usize>,
+    pub ids: Vec<String>,
+    pub sample: Option<usize>,
+    pub seed: u64,
+}
+
+/// A group whose alignment was chosen by random sampling, with its alignment once called.
+struct Sampled {
+    index: usize,
+    rows: Option<Vec<(String, String)>>,
+}
+
+/// Chooses the groups whose multiple sequence alignment is written, and writes them.
+///
+/// Groups chosen by index or identifier are written as soon as they are called. Groups are
+/// sampled with reservoir sampling, so that the sample is uniform without knowing the number of
+/// groups in advance, and are written by `finish`.
+pub struct MsaSelector<'a> {
+    opts: &'a MsaOpts,
+    ids: Vec<RecordIdentifier>,
+    rng: StdRng,
+    seen: usize,
+    reservoir: Vec<Sampled>,
+}
+
+impl<'a> MsaSelector<'a> {
+    /// Creates a new selector, creating the output directory if it does not exist.
+    pub fn new(opts: &'a MsaOpts) -> Result<Self> {
+        std::fs::create_dir_all(&opts.dir)
+            .with_context(|| format!("Could not create MSA directory {}", opts.dir))?;
+
+        // parse the identifiers once, in the same way as the identifiers in the index
+        let ids = opts
+            .ids
+            .iter()
+            .filter(|id| !id.is_empty())
+            .map(|id| RecordIdentifier::from_string(id))
+            .collect();
+
+        Ok(MsaSelector {
+            opts,
+            ids,
+            rng: StdRng::seed_from_u64(opts.seed),
+            seen: 0,
+            reservoir: Vec::new(),
+        })
+    }
+
+    /// Returns true if a group was chosen by index or identifier.
+    fn chosen(&self, group: &UMIGroup) -> bool {
+        self.opts.groups.contains(&group.index) || self.ids.contains(&group.id)
+    }
+
+    /// Decides whether the alignment of a group should be written. This must be called for each
+    /// group in order, before the group is called.
+    pub fn select(&mut self, group: &UMIGroup) -> bool {
+        if self.chosen(group) {
+            return true;
+        }
+
+        let Some(sample) = self.opts.sample else {
+            return false;
+        };
+        if group.records.len() < 2 {
+            return false;
+        }
+
+        self.seen += 1;
+        let sampled = Sampled {
+            index: group.index,
+            rows: None,
+        };
+
+        if self.reservoir.len() < sample {
+            self.reservoir.push(sampled);
+            return true;
+        }
+
+        // replace a random member of the reservoir, with probability sample / seen
+        let slot = self.rng.gen_range(0..self.seen);
+        if slot < sample {
+            self.reservoir[slot] = sampled;
+            return true;
+        }
+        false
+    }
+
+    /// Records the alignment of a called group, writing it if the group was chosen by index or
+    /// identifier, or keeping it if it is still in the sample. Groups without an alignment are
+    /// ignored.
+    pub fn record(&mut self, group: &mut UMIGroup) -> Result<()> {
+        let Some(rows) = group.msa.take() else {
+            return Ok(());
+        };
+
+        if self.chosen(group) {
+            return self.write(group.index, &rows);
+        }
+
+        // sampled groups may have been replaced in the reservoir since they were selected
+        if let Some(sampled) = self.reservoir.iter_mut().find(|s| s.index == group.index) {
+            sampled.rows = Some(rows);
+        }
+        Ok(())
+    }
+
+    /// Writes the alignments of every sampled group.
+    pub fn finish(self) -> Result<()> {
+        for sampled in self.reservoir.iter() {
+            if let Some(rows) = &sampled.rows {
+                self.write(sampled.index, rows)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes an alignment as aligned FASTA, to `group_<index>.fasta` in the output directory.
+    fn write(&self, index: usize, rows: &[(String, String)]) -> Result<()> {
+        let path = Path::new(&self.opts.dir).join(format!("group_{index}.fasta"));
+        let file = File::create(&path)
+            .with_context(|| format!("Could not create MSA file {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+
+        for (name, seq) in rows {
+            writeln!(writer, ">{name}\n{seq}")?;
+        }
+        writer.flush()?;
+
+        Ok(())
+    }
+}
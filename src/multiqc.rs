@@ -0,0 +1,134 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+
+use crate::summary::SummaryReport;
+
+/// The group size bins of the bar plot, as `(label, smallest size, largest size)`.
+const GROUP_SIZE_BINS: [(&str, usize, usize); 8] = [
+    ("1", 1, 1),
+    ("2", 2, 2),
+    ("3-5", 3, 5),
+    ("6-10", 6, 10),
+    ("11-20", 11, 20),
+    ("21-50", 21, 50),
+    ("51-100", 51, 100),
+    (">100", 101, usize::MAX),
+];
+
+/// Returns the name of a sample from the path of its input file, without its directory or its
+/// FASTQ and compression extensions.
+pub fn sample_name(file_path: &str) -> String {
+    let mut name = Path::new(file_path)
+        .file_name()
+        .map_or(file_path.into(), |n| n.to_string_lossy().into_owned());
+
+    for ext in [".gz", ".bgz", ".fastq", ".fq"] {
+        if let Some(stripped) = name.strip_suffix(ext) {
+            name.truncate(stripped.len());
+        }
+    }
+    name
+}
+
+/// Writes a MultiQC custom content file.
+fn write_mqc(path: &str, content: &Value) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Could not create {path}"))?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, content)
+        .with_context(|| format!("Could not write {path}"))?;
+    writeln!(writer)?;
+    info!("Wrote MultiQC report to {path}");
+    Ok(())
+}
+
+/// Writes the summary as MultiQC custom content, to `<prefix>.general_stats_mqc.json` and
+/// `<prefix>.group_sizes_mqc.json`. The first adds the read count, duplicate proportion and mean
+/// read length to the general statistics table, and the second is a bar plot of the number of
+/// groups of each size. The files of several samples are merged by MultiQC.
+///
+/// # Arguments
+///
+/// * `prefix` - The prefix of the output files.
+/// * `reports` - The summary of each sample to write.
+pub fn write_multiqc(prefix: &str, reports: &[SummaryReport]) -> Result<()> {
+    let stats: serde_json::Map<String, Value> = reports
+        .iter()
+        .map(|report| {
+            let row = json!({
+                "reads": report.duplicates.total_reads,
+                "percent_duplicates": report.duplicates.proportion_duplicate * 100.0,
+                "mean_length": report.metadata.avg_len,
+            });
+            (report.sample.clone(), row)
+        })
+        .collect();
+
+    let general_stats = json!({
+        "id": "nailpolish",
+        "plot_type": "generalstats",
+        "pconfig": {
+            "reads": {
+                "title": "Reads",
+                "description": "The number of reads which were grouped",
+                "format": "{:,.0f}",
+                "scale": "Blues",
+            },
+            "percent_duplicates": {
+                "title": "% Dups",
+                "description": "The percentage of reads in groups of more than one read",
+                "min": 0,
+                "max": 100,
+                "suffix": "%",
+                "scale": "RdYlGn-rev",
+            },
+            "mean_length": {
+                "title": "Mean length",
+                "description": "The mean length of the matched reads",
+                "format": "{:,.0f}",
+                "suffix": " bp",
+            },
+        },
+        "data": stats,
+    });
+    write_mqc(&format!("{prefix}.general_stats_mqc.json"), &general_stats)?;
+
+    let bins: serde_json::Map<String, Value> = reports
+        .iter()
+        .map(|report| {
+            let bins: serde_json::Map<String, Value> = GROUP_SIZE_BINS
+                .iter()
+                .map(|&(label, min, max)| {
+                    let groups: usize = report
+                        .duplicates
+                        .distribution
+                        .range(min..=max)
+                        .map(|(_, n)| n)
+                        .sum();
+                    (label.to_string(), json!(groups))
+                })
+                .collect();
+            (report.sample.clone(), Value::Object(bins))
+        })
+        .collect();
+
+    let group_sizes = json!({
+        "id": "nailpolish_group_sizes",
+        "section_name": "nailpolish group sizes",
+        "description": "The number of groups of duplicate reads of each size. Each group is a single molecule.",
+        "plot_type": "bargraph",
+        "pconfig": {
+            "id": "nailpolish_group_sizes_plot",
+            "title": "nailpolish: group sizes",
+            "ylab": "Groups",
+            "cpswitch_counts_label": "Groups",
+        },
+        // a list keeps the bins in order
+        "categories": GROUP_SIZE_BINS.iter().map(|(label, ..)| label).collect::<Vec<_>>(),
+        "data": bins,
+    });
+    write_mqc(&format!("{prefix}.group_sizes_mqc.json"), &group_sizes)
+}
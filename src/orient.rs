@@ -0,0 +1,284 @@
+use std::borrow::Cow;
+use std::fmt::Display;
+
+use serde::Serialize;
+
+use crate::align::{edit_distance, find_pattern};
+use crate::io::Record;
+
+/// The number of bases at each end of a read which are searched for a polyA/polyT tail or primer.
+pub(crate) const END_WINDOW: usize = 100;
+
+/// The minimum length of a homopolymer run for it to be considered a polyA/polyT tail.
+pub(crate) const MIN_TAIL_LEN: usize = 10;
+
+/// The maximum proportion of a primer which can be mismatched for it to be considered found.
+const MAX_PRIMER_ERROR: f64 = 0.2;
+
+/// Methods for detecting the orientation of each read in a group.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OrientMethod {
+    /// do not orient reads
+    None,
+
+    /// forward reads end in a polyA tail, and reverse reads start with a polyT tail
+    Polya,
+
+    /// forward reads start with the given primer, and reverse reads end with its reverse complement
+    Primer,
+
+    /// orient each read against the first read of its group, by edit distance
+    Align,
+}
+
+/// The orientation of a read, relative to the common strand of its group.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strand {
+    Forward,
+    Reverse,
+}
+
+impl Display for Strand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Strand::Forward => f.write_str("+"),
+            Strand::Reverse => f.write_str("-"),
+        }
+    }
+}
+
+/// Options which control how reads are oriented before consensus calling.
+///
+/// # Fields
+///
+/// * `method` - The method used to detect the orientation of each read
+/// * `primer` - The primer sequence, for `OrientMethod::Primer`
+#[derive(Clone, Debug, Serialize)]
+pub struct OrientOpts {
+    pub method: OrientMethod,
+    pub primer: Option<String>,
+}
+
+/// Returns the reverse complement of a DNA sequence. Bases other than `ACGTN` are unchanged.
+pub fn reverse_complement(seq: &str) -> String {
+    seq.bytes()
+        .rev()
+        .map(|b| match b {
+            b'A' => 'T',
+            b'C' => 'G',
+            b'G' => 'C',
+            b'T' => 'A',
+            b'a' => 't',
+            b'c' => 'g',
+            b'g' => 'c',
+            b't' => 'a',
+            _ => b as char,
+        })
+        .collect()
+}
+
+impl Record {
+    /// Returns this record on the given strand, reverse complementing the sequence and reversing
+    /// the qualities if required.
+    pub fn on_strand(&self, strand: Strand) -> Cow<'_, Record> {
+        match strand {
+            Strand::Forward => Cow::Borrowed(self),
+            Strand::Reverse => Cow::Owned(Record {
+                id: self.id.clone(),
+                seq: reverse_complement(&self.seq),
+                qual: self.qual.chars().rev().collect(),
+            }),
+        }
+    }
+}
+
+/// Finds the longest run of `base` within `seq`.
+///
+/// # Returns
+///
+/// A tuple of the start position and length of the run. If several runs are equally long, the
+/// leftmost is returned.
+pub(crate) fn longest_run(seq: &[u8], base: u8) -> (usize, usize) {
+    let mut longest = (0, 0);
+    let mut current = 0;
+    for (i, &b) in seq.iter().enumerate() {
+        if b.eq_ignore_ascii_case(&base) {
+            current += 1;
+            if current > longest.1 {
+                longest = (i + 1 - current, current);
+            }
+        } else {
+            current = 0;
+        }
+    }
+    longest
+}
+
+/// Detects the strand of a read from a polyA tail at its end, or a polyT tail at its start.
+fn polya_strand(seq: &[u8]) -> Option<Strand> {
+    let window = END_WINDOW.min(seq.len());
+    let (_, poly_a) = longest_run(&seq[seq.len() - window..], b'A');
+    let (_, poly_t) = longest_run(&seq[..window], b'T');
+
+    if poly_a.max(poly_t) < MIN_TAIL_LEN {
+        None
+    } else if poly_a >= poly_t {
+        Some(Strand::Forward)
+    } else {
+        Some(Strand::Reverse)
+    }
+}
+
+/// Detects the strand of a read from a primer at its start, or the reverse complement of the
+/// primer at its end.
+fn primer_strand(seq: &[u8], primer: &str) -> Option<Strand> {
+    let window = (END_WINDOW + primer.len()).min(seq.len());
+    let max_errors = (primer.len() as f64 * MAX_PRIMER_ERROR) as usize;
+
+    let (forward, _) = find_pattern(primer.as_bytes(), &seq[..window]);
+    let (reverse, _) = find_pattern(
+        reverse_complement(primer).as_bytes(),
+        &seq[seq.len() - window..],
+    );
+
+    if forward.min(reverse) > max_errors || forward == reverse {
+        None
+    } else if forward < reverse {
+        Some(Strand::Forward)
+    } else {
+        Some(Strand::Reverse)
+    }
+}
+
+/// Detects the strand of a single read, without reference to the rest of its group.
+fn read_strand(record: &Record, opts: &OrientOpts) -> Option<Strand> {
+    let seq = record.seq.as_bytes();
+    match opts.method {
+        OrientMethod::Polya => polya_strand(seq),
+        OrientMethod::Primer => primer_strand(seq, opts.primer.as_deref().unwrap_or_default()),
+        OrientMethod::None | OrientMethod::Align => None,
+    }
+}
+
+/// Detects the orientation of a single read which is not part of a group. Without other reads to
+/// align against, reads whose strand cannot be detected are assumed to be forward.
+pub fn orient_single(record: &Record, opts: &OrientOpts) -> Strand {
+    read_strand(record, opts).unwrap_or(Strand::Forward)
+}
+
+/// Detects the orientation of each read in a group.
+///
+/// Reads are first oriented individually using `opts.method`. Any reads whose strand cannot be
+/// detected this way, or every read if `opts.method` is `OrientMethod::Align`, are then oriented
+/// by aligning them in both orientations against a reference read: the first read with a
+/// detected strand, or otherwise the first read of the group.
+///
+/// # Returns
+///
+/// The strand of each read, where reads on `Strand::Reverse` should be reverse complemented
+/// before consensus calling.
+pub fn orient(records: &[&Record], opts: &OrientOpts) -> Vec<Strand> {
+    let detected: Vec<Option<Strand>> = records.iter().map(|r| read_strand(r, opts)).collect();
+
+    let (ref_idx, ref_strand) = detected
+        .iter()
+        .enumerate()
+        .find_map(|(i, s)| s.map(|s| (i, s)))
+        .unwrap_or((0, Strand::Forward));
+    let reference = records[ref_idx].on_strand(ref_strand);
+
+    records
+        .iter()
+        .zip(detected)
+        .enumerate()
+        .map(|(i, (record, strand))| {
+            if i == ref_idx {
+                return ref_strand;
+            }
+            if let Some(strand) = strand {
+                return strand;
+            }
+
+            let forward = edit_distance(reference.seq.as_bytes(), record.seq.as_bytes());
+            let reverse = edit_distance(
+                reference.seq.as_bytes(),
+                reverse_complement(&record.seq).as_bytes(),
+            );
+
+            if reverse < forward {
+                Strand::Reverse
+            } else {
+                Strand::Forward
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(seq: &str) -> Record {
+        Record {
+            id: "read".into(),
+            seq: seq.into(),
+            qual: "I".repeat(seq.len()),
+        }
+    }
+
+    fn opts(method: OrientMethod, primer: Option<&str>) -> OrientOpts {
+        OrientOpts {
+            method,
+            primer: primer.map(String::from),
+        }
+    }
+
+    /// A sequence without long homopolymer runs.
+    fn insert() -> String {
+        "GATTACACGTCCGGTAGCTA".repeat(10)
+    }
+
+    #[test]
+    fn reverse_complements() {
+        assert_eq!(reverse_complement("ACGTNacgt"), "acgtNACGT");
+
+        let rec = record("AACG");
+        let rev = rec.on_strand(Strand::Reverse);
+        assert_eq!(rev.seq, "CGTT");
+        assert_eq!(rec.on_strand(Strand::Forward).seq, "AACG");
+    }
+
+    #[test]
+    fn polya() {
+        let opts = opts(OrientMethod::Polya, None);
+        let forward = record(&format!("{}{}", insert(), "A".repeat(20)));
+        let reverse = record(&format!("{}{}", "T".repeat(20), insert()));
+
+        assert_eq!(orient_single(&forward, &opts), Strand::Forward);
+        assert_eq!(orient_single(&reverse, &opts), Strand::Reverse);
+        assert_eq!(orient_single(&record(&insert()), &opts), Strand::Forward);
+    }
+
+    #[test]
+    fn primer() {
+        let primer = "CTACACGACGCTCTTCCGATCT";
+        let opts = opts(OrientMethod::Primer, Some(primer));
+        let forward = record(&format!("{primer}{}", insert()));
+        let reverse = record(&format!("{}{}", insert(), reverse_complement(primer)));
+
+        assert_eq!(orient_single(&forward, &opts), Strand::Forward);
+        assert_eq!(orient_single(&reverse, &opts), Strand::Reverse);
+    }
+
+    #[test]
+    fn align() {
+        let seq = insert();
+        let first = record(&seq);
+        let second = record(&reverse_complement(&seq));
+        let third = record(&seq[5..]);
+
+        let strands = orient(&[&first, &second, &third], &opts(OrientMethod::Align, None));
+        assert_eq!(strands, [Strand::Forward, Strand::Reverse, Strand::Forward]);
+    }
+}
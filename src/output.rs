@@ -0,0 +1,509 @@
+use std::borrow::Cow;
+use std::io::Write;
+
+use anyhow::{ensure, Result};
+use serde::Serialize;
+
+use crate::bgzf::BgzfWriter;
+use crate::duplicates::RecordIdentifier;
+use crate::io::Record;
+use crate::split::SplitWriter;
+
+/// The format of the reads written by `call` and `group`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// .fastq, with metadata in the read name
+    Fastq,
+
+    /// .fasta, with metadata in the read name. quality values are not preserved
+    Fasta,
+
+    /// unaligned SAM, with metadata as SAM tags
+    Sam,
+
+    /// unaligned BAM, with metadata as SAM tags
+    Ubam,
+}
+
+/// A SAM tag value, typed as in the SAM specification.
+enum TagValue<'a> {
+    Char(u8),
+    Int(i32),
+    Float(f32),
+    String(Cow<'a, str>),
+}
+
+/// A read split into its name and SAM tags, ready to be written as SAM or BAM.
+struct SamRecord<'a> {
+    name: &'a str,
+    seq: &'a str,
+    qual: &'a str,
+    tags: Vec<([u8; 2], TagValue<'a>)>,
+}
+
+impl<'a> SamRecord<'a> {
+    /// Splits a record into its name and SAM tags.
+    ///
+    /// The barcode and UMI of `id` become the `CB` and `UB` tags. Any `TAG:TYPE:VALUE` fields of
+    /// the record identifier after the name, such as those added by `Record::add_metadata`, become
+    /// tags of that type, and any other text in the identifier is kept as a `CO` tag.
+    fn new(rec: &'a Record, id: &'a RecordIdentifier) -> Self {
+        let mut fields = rec.id.split_whitespace();
+        let name = fields.next().unwrap_or_default();
+
+        let mut tags = vec![(*b"CB", TagValue::String(Cow::Borrowed(id.head.as_str())))];
+        if !id.tail.is_empty() {
+            tags.push((*b"UB", TagValue::String(Cow::Borrowed(id.tail.as_str()))));
+        }
+
+        let mut comments = Vec::new();
+        for field in fields {
+            match parse_tag(field) {
+                // each tag may only appear once, so keep the first occurrence
+                Some((tag, value)) if !tags.iter().any(|(t, _)| *t == tag) => {
+                    tags.push((tag, value))
+                }
+                _ => comments.push(field),
+            }
+        }
+        if !comments.is_empty() {
+            tags.push((*b"CO", TagValue::String(Cow::Owned(comments.join(" ")))));
+        }
+
+        SamRecord {
+            name,
+            seq: &rec.seq,
+            qual: &rec.qual,
+            tags,
+        }
+    }
+
+    /// Writes this record as a line of an unaligned SAM file.
+    fn write_sam(&self, writer: &mut impl Write) -> Result<()> {
+        let seq = if self.seq.is_empty() { "*" } else { self.seq };
+        let qual = if self.qual.is_empty() { "*" } else { self.qual };
+        write!(
+            writer,
+            "{}\t4\t*\t0\t0\t*\t*\t0\t0\t{seq}\t{qual}",
+            self.name
+        )?;
+
+        for (tag, value) in self.tags.iter() {
+            let tag = std::str::from_utf8(tag).expect("Tags should be ASCII");
+            match value {
+                TagValue::Char(c) => write!(writer, "\t{tag}:A:{}", *c as char)?,
+                TagValue::Int(i) => write!(writer, "\t{tag}:i:{i}")?,
+                TagValue::Float(f) => write!(writer, "\t{tag}:f:{f}")?,
+                TagValue::String(s) => write!(writer, "\t{tag}:Z:{s}")?,
+            }
+        }
+        writer.write_all(b"\n")?;
+
+        Ok(())
+    }
+
+    /// Writes this record as an unaligned BAM record.
+    fn write_bam(&self, writer: &mut impl Write) -> Result<()> {
+        let l_seq = self.seq.len();
+        // read names are limited to 254 bytes in BAM
+        let name = &self.name.as_bytes()[..self.name.len().min(254)];
+        let mut data = Vec::with_capacity(64 + name.len() + 2 * l_seq);
+
+        data.extend_from_slice(&(-1i32).to_le_bytes()); // refID
+        data.extend_from_slice(&(-1i32).to_le_bytes()); // pos
+        data.push((name.len() + 1) as u8); // l_read_name
+        data.push(0); // mapq
+        data.extend_from_slice(&4680u16.to_le_bytes()); // bin, reg2bin(-1, 0)
+        data.extend_from_slice(&0u16.to_le_bytes()); // n_cigar_op
+        data.extend_from_slice(&4u16.to_le_bytes()); // flag: unmapped
+        data.extend_from_slice(&(l_seq as u32).to_le_bytes());
+        data.extend_from_slice(&(-1i32).to_le_bytes()); // next_refID
+        data.extend_from_slice(&(-1i32).to_le_bytes()); // next_pos
+        data.extend_from_slice(&0i32.to_le_bytes()); // tlen
+
+        data.extend_from_slice(name);
+        data.push(0);
+
+        // bases are packed two to a byte, high nibble first
+        for pair in self.seq.as_bytes().chunks(2) {
+            let high = base_code(pair[0]);
+            let low = pair.get(1).map_or(0, |&b| base_code(b));
+            data.push((high << 4) | low);
+        }
+
+        if self.qual.len() == l_seq {
+            data.extend(self.qual.bytes().map(|q| q.saturating_sub(33)));
+        } else {
+            data.resize(data.len() + l_seq, 0xff);
+        }
+
+        for (tag, value) in self.tags.iter() {
+            data.extend_from_slice(tag);
+            match value {
+                TagValue::Char(c) => data.extend_from_slice(&[b'A', *c]),
+                TagValue::Int(i) => {
+                    data.push(b'i');
+                    data.extend_from_slice(&i.to_le_bytes());
+                }
+                TagValue::Float(f) => {
+                    data.push(b'f');
+                    data.extend_from_slice(&f.to_le_bytes());
+                }
+                TagValue::String(s) => {
+                    data.push(b'Z');
+                    data.extend_from_slice(s.as_bytes());
+                    data.push(0);
+                }
+            }
+        }
+
+        writer.write_all(&(data.len() as u32).to_le_bytes())?;
+        writer.write_all(&data)?;
+
+        Ok(())
+    }
+}
+
+/// Parses a `TAG:TYPE:VALUE` field of a record identifier as a SAM tag. Only the `A`, `i`, `f`
+/// and `Z` types are supported.
+fn parse_tag(field: &str) -> Option<([u8; 2], TagValue<'_>)> {
+    let bytes = field.as_bytes();
+    if bytes.len() < 5
+        || !bytes[0].is_ascii_alphabetic()
+        || !bytes[1].is_ascii_alphanumeric()
+        || bytes[2] != b':'
+        || bytes[4] != b':'
+    {
+        return None;
+    }
+
+    let tag = [bytes[0], bytes[1]];
+    let value = &field[5..];
+    let value = match bytes[3] {
+        b'A' if value.len() == 1 => TagValue::Char(value.as_bytes()[0]),
+        b'i' => TagValue::Int(value.parse().ok()?),
+        b'f' => TagValue::Float(value.parse().ok()?),
+        b'Z' => TagValue::String(Cow::Borrowed(value)),
+        _ => return None,
+    };
+
+    Some((tag, value))
+}
+
+/// Returns the 4-bit BAM encoding of a base.
+fn base_code(base: u8) -> u8 {
+    match base.to_ascii_uppercase() {
+        b'=' => 0,
+        b'A' => 1,
+        b'C' => 2,
+        b'M' => 3,
+        b'G' => 4,
+        b'R' => 5,
+        b'S' => 6,
+        b'V' => 7,
+        b'T' => 8,
+        b'W' => 9,
+        b'Y' => 10,
+        b'H' => 11,
+        b'K' => 12,
+        b'D' => 13,
+        b'B' => 14,
+        _ => 15,
+    }
+}
+
+/// Returns the header of an unaligned SAM file, which has no reference sequences.
+fn sam_header() -> String {
+    // a resumed run continues the output of the original run, so writes the same header
+    let command = std::env::args()
+        .filter(|arg| arg != "--resume")
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(
+        "@HD\tVN:1.6\tSO:unsorted\n@PG\tID:nailpolish\tPN:nailpolish\tVN:{}\tCL:{command}\n",
+        crate::cli::VERSION
+    )
+}
+
+/// How the output is compressed.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputCompression {
+    /// no compression
+    None,
+
+    /// multi-member gzip, readable by any gzip decompressor
+    Gzip,
+
+    /// blocked gzip, as used by BAM files and readable by any gzip decompressor
+    Bgzf,
+}
+
+impl OutputCompression {
+    /// Returns the compression given on the command line, or otherwise the compression implied
+    /// by the extension of the output path: `.gz` for gzip, and `.bgz` or `.bgzf` for BGZF.
+    pub fn infer(compress: Option<OutputCompression>, path: &Option<String>) -> Self {
+        if let Some(compress) = compress {
+            return compress;
+        }
+
+        match path.as_deref() {
+            Some(p) if p.ends_with(".gz") => OutputCompression::Gzip,
+            Some(p) if p.ends_with(".bgz") || p.ends_with(".bgzf") => OutputCompression::Bgzf,
+            _ => OutputCompression::None,
+        }
+    }
+}
+
+enum Sink<W: Write> {
+    Plain(W),
+    Compressed(BgzfWriter<W>),
+}
+
+impl<W: Write> Write for Sink<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Sink::Plain(w) => w.write(buf),
+            Sink::Compressed(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Sink::Plain(w) => w.flush(),
+            Sink::Compressed(w) => w.flush(),
+        }
+    }
+}
+
+/// Writes reads in any `OutputFormat`, optionally compressed.
+///
+/// FASTQ and FASTA records are separated by newlines, without a trailing newline. SAM and BAM
+/// files start with a header, which is written when the writer is created.
+pub struct RecordWriter<W: Write> {
+    sink: Sink<W>,
+    format: OutputFormat,
+    /// The number of (uncompressed) bytes written so far
+    written: u64,
+    /// A buffer which each record is formatted into before it is written
+    buf: Vec<u8>,
+}
+
+impl<W: Write> RecordWriter<W> {
+    /// Creates a new writer, writing a header if the format requires one. BAM output is always
+    /// compressed with BGZF.
+    pub fn new(writer: W, format: OutputFormat, compression: OutputCompression) -> Result<Self> {
+        let mut writer = Self::append(writer, format, compression, 0)?;
+
+        match format {
+            OutputFormat::Sam => writer.buf.extend_from_slice(sam_header().as_bytes()),
+            OutputFormat::Ubam => {
+                let header = sam_header();
+                writer.buf.extend_from_slice(b"BAM\x01");
+                writer
+                    .buf
+                    .extend_from_slice(&(header.len() as i32).to_le_bytes());
+                writer.buf.extend_from_slice(header.as_bytes());
+                writer.buf.extend_from_slice(&0i32.to_le_bytes()); // n_ref
+            }
+            _ => {}
+        }
+        writer.write_buf()?;
+
+        Ok(writer)
+    }
+
+    /// Creates a writer which continues an output file, where `written` bytes, including any
+    /// header, have already been written by a previous writer. No header is written.
+    ///
+    /// Compressed output is continued as new gzip members, which are read as part of the same
+    /// stream by any gzip decompressor.
+    pub fn append(
+        writer: W,
+        format: OutputFormat,
+        compression: OutputCompression,
+        written: u64,
+    ) -> Result<Self> {
+        let compression = if format == OutputFormat::Ubam {
+            ensure!(
+                compression != OutputCompression::Gzip,
+                "BAM output must be compressed with BGZF, not gzip"
+            );
+            OutputCompression::Bgzf
+        } else {
+            compression
+        };
+
+        let sink = match compression {
+            OutputCompression::None => Sink::Plain(writer),
+            OutputCompression::Gzip => Sink::Compressed(BgzfWriter::gzip(writer)),
+            OutputCompression::Bgzf => Sink::Compressed(BgzfWriter::new(writer)),
+        };
+
+        Ok(RecordWriter {
+            sink,
+            format,
+            written,
+            buf: Vec::new(),
+        })
+    }
+
+    /// Writes a single record.
+    ///
+    /// # Arguments
+    ///
+    /// * `rec` - The record to write.
+    /// * `id` - The identifier of the group which the record belongs to, which gives the
+    ///   barcode and UMI tags in SAM and BAM output.
+    pub fn write(&mut self, rec: &Record, id: &RecordIdentifier) -> Result<()> {
+        match self.format {
+            OutputFormat::Fastq | OutputFormat::Fasta => {
+                // add a newline at the start, unless this is the first record in the file
+                if self.written > 0 {
+                    self.buf.push(b'\n');
+                }
+                if self.format == OutputFormat::Fastq {
+                    rec.write_fastq(&mut self.buf)?;
+                } else {
+                    rec.write_fasta(&mut self.buf)?;
+                }
+            }
+            OutputFormat::Sam => SamRecord::new(rec, id).write_sam(&mut self.buf)?,
+            OutputFormat::Ubam => SamRecord::new(rec, id).write_bam(&mut self.buf)?,
+        }
+
+        self.write_buf()
+    }
+
+    /// Writes the formatted contents of `buf` to the output.
+    fn write_buf(&mut self) -> Result<()> {
+        self.sink.write_all(&self.buf)?;
+        self.written += self.buf.len() as u64;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Returns the number of bytes written so far, before any compression. For uncompressed
+    /// output, this is the length of the output once flushed.
+    pub fn position(&self) -> u64 {
+        self.written
+    }
+
+    /// Flushes the output. Compressed output keeps any partial block buffered.
+    pub fn flush(&mut self) -> Result<()> {
+        self.sink.flush()?;
+        Ok(())
+    }
+
+    /// Writes any remaining output, such as the end-of-file marker of a BGZF file, and flushes.
+    pub fn finish(self) -> Result<()> {
+        match self.sink {
+            Sink::Plain(mut w) => w.flush()?,
+            Sink::Compressed(w) => {
+                w.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where output reads are written: either to a single file or standard output, or split into a
+/// directory of files.
+pub enum OutputWriter<W: Write> {
+    Single(RecordWriter<W>),
+    Split(SplitWriter),
+}
+
+impl<W: Write> OutputWriter<W> {
+    /// Writes a single record.
+    pub fn write(&mut self, rec: &Record, id: &RecordIdentifier) -> Result<()> {
+        match self {
+            OutputWriter::Single(w) => w.write(rec, id),
+            OutputWriter::Split(w) => w.write(rec, id),
+        }
+    }
+
+    /// Returns the number of bytes written so far, before any compression.
+    pub fn position(&self) -> u64 {
+        match self {
+            OutputWriter::Single(w) => w.position(),
+            OutputWriter::Split(w) => w.position(),
+        }
+    }
+
+    /// Flushes the output.
+    pub fn flush(&mut self) -> Result<()> {
+        match self {
+            OutputWriter::Single(w) => w.flush(),
+            OutputWriter::Split(w) => w.flush(),
+        }
+    }
+
+    /// Writes any remaining output, and flushes.
+    pub fn finish(self) -> Result<()> {
+        match self {
+            OutputWriter::Single(w) => w.finish(),
+            OutputWriter::Split(w) => w.finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A record tagged as by `Record::add_metadata`, with some other text in its identifier.
+    fn record() -> (Record, RecordIdentifier) {
+        let rec = Record {
+            id: "read1 UT:Z:CON_3 UG:i:7 QL:f:20.5 ST:A:+ some comment".into(),
+            seq: "ACGTN".into(),
+            qual: "IIII#".into(),
+        };
+        let id = RecordIdentifier {
+            head: "AAAA".into(),
+            tail: "CCCC".into(),
+        };
+        (rec, id)
+    }
+
+    #[test]
+    fn sam_record() {
+        let (rec, id) = record();
+        let mut line = Vec::new();
+        SamRecord::new(&rec, &id).write_sam(&mut line).unwrap();
+
+        assert_eq!(
+            String::from_utf8(line).unwrap(),
+            "read1\t4\t*\t0\t0\t*\t*\t0\t0\tACGTN\tIIII#\tCB:Z:AAAA\tUB:Z:CCCC\tUT:Z:CON_3\t\
+             UG:i:7\tQL:f:20.5\tST:A:+\tCO:Z:some comment\n"
+        );
+    }
+
+    #[test]
+    fn bam_record() {
+        let (rec, id) = record();
+        let mut data = Vec::new();
+        SamRecord::new(&rec, &id).write_bam(&mut data).unwrap();
+
+        let block_size = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+        assert_eq!(block_size, data.len() - 4);
+        let data = &data[4..];
+
+        // l_read_name includes the NUL, and l_seq is the number of bases
+        assert_eq!(data[8], 6);
+        assert_eq!(u16::from_le_bytes([data[14], data[15]]), 4);
+        assert_eq!(u32::from_le_bytes(data[16..20].try_into().unwrap()), 5);
+        assert_eq!(&data[32..38], b"read1\0");
+
+        // bases are packed two to a byte, and qualities are offset by 33
+        assert_eq!(&data[38..41], &[0x12, 0x48, 0xf0]);
+        assert_eq!(&data[41..46], &[40, 40, 40, 40, 2]);
+
+        let tags = &data[46..];
+        assert!(tags.starts_with(b"CBZAAAA\0UBZCCCC\0UTZCON_3\0"));
+        assert!(tags.windows(7).any(|w| w == b"UGi\x07\0\0\0"));
+        assert!(tags.windows(4).any(|w| w == b"STA+"));
+        assert!(tags.ends_with(b"COZsome comment\0"));
+    }
+}
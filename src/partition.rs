@@ -0,0 +1,216 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use indexmap::IndexMap;
+use needletail::parser::FastqReader;
+use needletail::FastxReader;
+use tempfile::{tempfile, tempfile_in};
+
+use crate::duplicates::RecordIdentifier;
+use crate::io::Record;
+
+/// How the reads of each group are read from the input file.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadStrategy {
+    /// stream the input, and seek to the other reads of each group when its first read is found
+    Random,
+
+    /// make one sequential pass over the input, splitting reads into temporary partition files
+    /// by group, and then group each partition in memory. this avoids seeking, but groups are
+    /// not written in input order
+    Partition,
+}
+
+/// The group index stored for reads which were filtered out during indexing.
+const IGNORED: u64 = u64::MAX;
+
+/// Writes reads to temporary partition files during a sequential pass over the input, so that
+/// every read of a group is in the same partition.
+///
+/// Each read is stored as its group index, its identifier and its raw FASTQ record, each
+/// prefixed by its length where needed. Reads which were filtered out during indexing are kept
+/// in a separate partition, in input order.
+pub struct PartitionWriter {
+    partitions: Vec<BufWriter<File>>,
+    ignored: BufWriter<File>,
+}
+
+impl PartitionWriter {
+    /// Creates `n` empty partitions, which are removed once they are dropped. Together, the
+    /// partitions hold about a full copy of the input.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - The number of partitions
+    /// * `dir` - The directory to create the partitions in, or `None` for the system temporary
+    ///   directory
+    pub fn new(n: usize, dir: Option<&Path>) -> Result<Self> {
+        let create = || -> Result<BufWriter<File>> {
+            let file = match dir {
+                Some(dir) => tempfile_in(dir),
+                None => tempfile(),
+            };
+            Ok(BufWriter::new(
+                file.context("Could not create temporary partition file")?,
+            ))
+        };
+
+        Ok(PartitionWriter {
+            partitions: (0..n.max(1)).map(|_| create()).collect::<Result<_>>()?,
+            ignored: create()?,
+        })
+    }
+
+    /// Adds a read to the partition of its group, or to the ignored partition if `group` is
+    /// `None`.
+    ///
+    /// # Arguments
+    ///
+    /// * `group` - The index of the group of the read, or `None` for a filtered read
+    /// * `id` - The identifier of the read, from the index
+    /// * `raw` - The raw FASTQ record, as it appears in the input file
+    pub fn add(&mut self, group: Option<usize>, id: &str, raw: &[u8]) -> Result<()> {
+        let writer = match group {
+            Some(_) => {
+                let mut hasher = DefaultHasher::new();
+                id.hash(&mut hasher);
+                let n = self.partitions.len();
+                &mut self.partitions[(hasher.finish() % n as u64) as usize]
+            }
+            None => &mut self.ignored,
+        };
+
+        let group = group.map_or(IGNORED, |g| g as u64);
+        writer.write_all(&group.to_le_bytes())?;
+        writer.write_all(&(id.len() as u32).to_le_bytes())?;
+        writer.write_all(id.as_bytes())?;
+        writer.write_all(&(raw.len() as u32).to_le_bytes())?;
+        writer.write_all(raw)?;
+        Ok(())
+    }
+
+    /// Finishes writing, returning the partitions to be read back.
+    pub fn finish(self) -> Result<Partitions> {
+        let rewind = |w: BufWriter<File>| -> Result<File> {
+            let mut file = w.into_inner()?;
+            file.seek(SeekFrom::Start(0))?;
+            Ok(file)
+        };
+
+        let mut files = self
+            .partitions
+            .into_iter()
+            .map(rewind)
+            .collect::<Result<Vec<_>>>()?;
+        // the ignored partition is read last
+        files.push(rewind(self.ignored)?);
+        files.reverse();
+
+        Ok(Partitions {
+            files,
+            groups: Vec::new(),
+        })
+    }
+}
+
+/// A group read back from a partition.
+///
+/// # Fields
+///
+/// * `index` - The index of the group, or `None` for a read which was filtered out
+/// * `id` - The identifier of the group
+/// * `records` - The reads of the group, in input order
+pub struct PartitionGroup {
+    pub index: Option<usize>,
+    pub id: RecordIdentifier,
+    pub records: Vec<Record>,
+}
+
+/// The partitions written by a `PartitionWriter`, which are grouped one at a time.
+pub struct Partitions {
+    /// the partitions which have not yet been read, in reverse order
+    files: Vec<File>,
+    /// the groups of the current partition which have not yet been returned, in reverse order
+    groups: Vec<PartitionGroup>,
+}
+
+impl Partitions {
+    /// Returns the next group. The groups of each partition are returned in order of their index,
+    /// and the reads which were filtered out are returned last, each as its own group.
+    pub fn next_group(&mut self) -> Result<Option<PartitionGroup>> {
+        while self.groups.is_empty() {
+            let Some(file) = self.files.pop() else {
+                return Ok(None);
+            };
+            self.groups = read_partition(file)?;
+        }
+        Ok(self.groups.pop())
+    }
+}
+
+/// Reads a partition, grouping its reads in memory.
+///
+/// # Arguments
+///
+/// * `file` - The partition file.
+///
+/// # Returns
+///
+/// The groups of the partition, in reverse order.
+fn read_partition(file: File) -> Result<Vec<PartitionGroup>> {
+    let mut reader = BufReader::new(file);
+    let mut groups: IndexMap<u64, PartitionGroup> = IndexMap::new();
+    let mut singles = Vec::new();
+
+    let mut header = [0u8; 12];
+    loop {
+        match reader.read_exact(&mut header[..8]) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            r => r.context("Could not read temporary partition file")?,
+        }
+        reader.read_exact(&mut header[8..12])?;
+        let group = u64::from_le_bytes(header[..8].try_into().expect("8 bytes"));
+        let id_len = u32::from_le_bytes(header[8..12].try_into().expect("4 bytes")) as usize;
+
+        let mut id = vec![0; id_len];
+        reader.read_exact(&mut id)?;
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len)?;
+        let mut raw = vec![0; u32::from_le_bytes(len) as usize];
+        reader.read_exact(&mut raw)?;
+
+        let mut fq_reader = FastqReader::new(&raw[..]);
+        let rec = fq_reader.next().context("Unexpected EOF")??;
+        let rec = Record::try_from(rec).context("Could not perform utf8 conversions")?;
+        let id = RecordIdentifier::from_string(&String::from_utf8(id)?);
+
+        if group == IGNORED {
+            singles.push(PartitionGroup {
+                index: None,
+                id,
+                records: vec![rec],
+            });
+            continue;
+        }
+
+        groups
+            .entry(group)
+            .or_insert_with(|| PartitionGroup {
+                index: Some(group as usize),
+                id,
+                records: Vec::new(),
+            })
+            .records
+            .push(rec);
+    }
+
+    let mut groups: Vec<PartitionGroup> = groups.into_values().collect();
+    groups.append(&mut singles);
+    groups.sort_by_key(|g| g.index.unwrap_or(usize::MAX));
+    groups.reverse();
+    Ok(groups)
+}
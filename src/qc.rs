@@ -0,0 +1,116 @@
+use std::fs::File;
+
+use anyhow::{Context, Result};
+use csv::{Writer, WriterBuilder};
+use serde::Serialize;
+
+use crate::io::{Record, UMIGroup};
+
+/// The minimum proportion of reads which must agree with the consensus base of an alignment
+/// column for the column to be well supported.
+const MIN_COLUMN_SUPPORT: f64 = 0.5;
+
+/// Quality control statistics for the consensus read of a single group.
+///
+/// # Fields
+///
+/// * `group` - The index of the group, as in the `UG:i` tag
+/// * `id` - The identifier of the group, typically `BC_UMI`
+/// * `group_size` - The number of reads in the group
+/// * `consensus_length` - The length of the consensus read
+/// * `mean_identity` - The mean identity of each read used for calling to the consensus, in the
+///   multiple sequence alignment of the group
+/// * `low_support_columns` - The number of consensus bases which fewer than half of the reads
+///   used for calling agree with
+/// * `mean_quality` - The mean PHRED quality of the consensus read
+#[derive(Serialize, Debug)]
+pub struct ConsensusQc {
+    pub group: usize,
+    pub id: String,
+    pub group_size: usize,
+    pub consensus_length: usize,
+    pub mean_identity: f64,
+    pub low_support_columns: usize,
+    pub mean_quality: f64,
+}
+
+impl ConsensusQc {
+    /// Creates the QC statistics of a group from its consensus read (before any metadata is
+    /// added) and the alignment statistics given by `alignment_stats`.
+    pub fn new(group: &UMIGroup, consensus: &Record, stats: (f64, usize)) -> Self {
+        let (mean_identity, low_support_columns) = stats;
+        ConsensusQc {
+            group: group.index,
+            id: group.id.to_string(),
+            group_size: group.records.len(),
+            consensus_length: consensus.len(),
+            mean_identity,
+            low_support_columns,
+            mean_quality: consensus.phred_quality_avg(),
+        }
+    }
+}
+
+/// Computes the identity of each read to the consensus, and the number of poorly supported
+/// consensus bases, from a multiple sequence alignment.
+///
+/// # Arguments
+///
+/// * `msa` - The rows of the multiple sequence alignment, with gaps as `-`. The last row is the
+///   consensus, and every other row is a read.
+///
+/// # Returns
+///
+/// A tuple of the mean identity of each read to the consensus, and the number of low support
+/// columns. The identity of a read is the proportion of columns which match the consensus, out
+/// of those where either the read or the consensus has a base.
+pub fn alignment_stats(msa: &[&[u8]]) -> (f64, usize) {
+    let Some((consensus, reads)) = msa.split_last() else {
+        return (1.0, 0);
+    };
+    if reads.is_empty() {
+        return (1.0, 0);
+    }
+
+    let mut support = vec![0usize; consensus.len()];
+    let mut total_identity = 0.0;
+
+    for read in reads {
+        let mut matches = 0usize;
+        let mut aligned = 0usize;
+
+        for (col, (&r, &c)) in read.iter().zip(consensus.iter()).enumerate() {
+            if r == b'-' && c == b'-' {
+                continue;
+            }
+            aligned += 1;
+            if r == c {
+                matches += 1;
+                support[col] += 1;
+            }
+        }
+
+        total_identity += if aligned == 0 {
+            1.0
+        } else {
+            matches as f64 / aligned as f64
+        };
+    }
+
+    let min_support = reads.len() as f64 * MIN_COLUMN_SUPPORT;
+    let low_support = consensus
+        .iter()
+        .zip(support)
+        .filter(|&(&c, s)| c != b'-' && (s as f64) < min_support)
+        .count();
+
+    (total_identity / reads.len() as f64, low_support)
+}
+
+/// Creates a writer for a tab-separated QC report, with a header row.
+pub fn qc_writer(path: &str) -> Result<Writer<File>> {
+    WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_path(path)
+        .with_context(|| format!("Could not create QC report {path}"))
+}
@@ -0,0 +1,83 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use anyhow::{ensure, Context, Result};
+
+use crate::duplicates::{DuplicateMap, RecordIdentifier};
+
+/// Chooses which groups of duplicate reads are processed by `call` and `group`.
+///
+/// # Fields
+///
+/// * `barcodes` - If set, only groups with one of these barcodes are selected
+/// * `min_size` - The minimum number of reads in a selected group
+/// * `max_size` - If set, the maximum number of reads in a selected group
+#[derive(Default, Debug)]
+pub struct GroupSelection {
+    pub barcodes: Option<HashSet<String>>,
+    pub min_size: usize,
+    pub max_size: Option<usize>,
+}
+
+impl GroupSelection {
+    /// Returns true if every group is selected.
+    pub fn is_all(&self) -> bool {
+        self.barcodes.is_none() && self.min_size <= 1 && self.max_size.is_none()
+    }
+
+    /// Returns true if the group with the given identifier and number of reads is selected.
+    pub fn selects(&self, id: &RecordIdentifier, size: usize) -> bool {
+        size >= self.min_size
+            && self.max_size.is_none_or(|max| size <= max)
+            && self
+                .barcodes
+                .as_ref()
+                .is_none_or(|barcodes| barcodes.contains(&id.head))
+    }
+}
+
+impl DuplicateMap {
+    /// Logs the number of groups which are selected. Unselected groups are kept in the map, so
+    /// that every group keeps the index which it has when all groups are selected, and are skipped
+    /// without being read.
+    pub fn log_selected(&self, selection: &GroupSelection) {
+        if selection.is_all() {
+            return;
+        }
+
+        let selected = self
+            .by_id
+            .iter()
+            .filter(|(id, group)| selection.selects(id, group.len()))
+            .count();
+        info!("Selected {selected} of {} groups", self.by_id.len());
+    }
+}
+
+/// Reads a list of barcodes, one per line. Only the first tab-separated column of each line is
+/// used, so that tables such as the output of `summary --cell-stats` can be given directly.
+/// Empty lines are skipped.
+///
+/// # Arguments
+///
+/// * `path` - The path of the barcode list.
+pub fn read_barcodes(path: &str) -> Result<HashSet<String>> {
+    let file = File::open(path).with_context(|| format!("Unable to open barcode list {path}"))?;
+
+    let mut barcodes = HashSet::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let Some(barcode) = line.split('\t').next().map(str::trim) else {
+            continue;
+        };
+        if !barcode.is_empty() {
+            barcodes.insert(barcode.to_string());
+        }
+    }
+
+    ensure!(!barcodes.is_empty(), "The barcode list {path} is empty");
+    info!("Read {} barcodes from {path}", barcodes.len());
+
+    Ok(barcodes)
+}
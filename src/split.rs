@@ -0,0 +1,275 @@
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use csv::WriterBuilder;
+use indexmap::IndexMap;
+use serde::Serialize;
+
+use crate::duplicates::RecordIdentifier;
+use crate::io::Record;
+use crate::output::{OutputCompression, OutputFormat, RecordWriter};
+
+/// How output reads are split into separate files.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SplitBy {
+    /// write one file per cell barcode
+    Barcode,
+}
+
+/// A single output file of a `SplitWriter`, which is only open while it is in use.
+///
+/// # Fields
+///
+/// * `name` - The file name, relative to the output directory
+/// * `reads` - The number of reads written to the file
+/// * `written` - The number of bytes written to the file, as of when it was last closed
+/// * `last_used` - When the file was last written to, for choosing which file to close
+/// * `writer` - The writer, if the file is open
+struct SplitFile {
+    name: String,
+    reads: usize,
+    written: u64,
+    last_used: u64,
+    writer: Option<RecordWriter<BufWriter<File>>>,
+}
+
+/// A row of the manifest written by a `SplitWriter`.
+#[derive(Serialize)]
+struct ManifestRow<'a> {
+    barcode: &'a str,
+    file: &'a str,
+    reads: usize,
+}
+
+/// Writes reads to a directory, with one file per cell barcode.
+///
+/// There may be many more barcodes than the number of files which can be open at once, so at most
+/// `max_open` files are kept open. When another file is needed, the least recently used file is
+/// closed, and is appended to if it is needed again. A manifest listing the number of reads in
+/// each file is written to `manifest.tsv` by `finish`.
+pub struct SplitWriter {
+    dir: PathBuf,
+    format: OutputFormat,
+    compression: OutputCompression,
+    max_open: usize,
+    files: IndexMap<String, SplitFile>,
+    /// the lowercase names of every file, so that no two barcodes share a file, even on
+    /// case-insensitive filesystems
+    names: HashSet<String>,
+    open: usize,
+    clock: u64,
+}
+
+impl SplitWriter {
+    /// Creates a new writer, creating the output directory if it does not exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The output directory.
+    /// * `format` - The format of each output file.
+    /// * `compression` - How each output file is compressed.
+    /// * `max_open` - The maximum number of files to keep open at once.
+    pub fn new(
+        dir: &str,
+        format: OutputFormat,
+        compression: OutputCompression,
+        max_open: usize,
+    ) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Could not create output directory {dir}"))?;
+
+        Ok(SplitWriter {
+            dir: dir.into(),
+            format,
+            compression,
+            max_open: max_open.max(1),
+            files: IndexMap::new(),
+            names: HashSet::new(),
+            open: 0,
+            clock: 0,
+        })
+    }
+
+    /// Returns a new file name for a barcode. Characters which may not be valid in a file name
+    /// are replaced with underscores, and if this gives the name of another barcode's file, a
+    /// numbered suffix is added.
+    fn file_name(&mut self, barcode: &str) -> String {
+        let mut stem: String = barcode
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        if stem.is_empty() {
+            stem.push_str("unknown");
+        }
+
+        let mut extension = match self.format {
+            OutputFormat::Fastq => ".fastq",
+            OutputFormat::Fasta => ".fasta",
+            OutputFormat::Sam => ".sam",
+            OutputFormat::Ubam => ".bam",
+        }
+        .to_string();
+        if self.format != OutputFormat::Ubam {
+            match self.compression {
+                OutputCompression::None => {}
+                OutputCompression::Gzip => extension.push_str(".gz"),
+                OutputCompression::Bgzf => extension.push_str(".bgz"),
+            }
+        }
+
+        let mut name = format!("{stem}{extension}");
+        let mut n = 1;
+        while !self.names.insert(name.to_lowercase()) {
+            n += 1;
+            name = format!("{stem}_{n}{extension}");
+        }
+        name
+    }
+
+    /// Closes the least recently used open file.
+    fn close_oldest(&mut self) -> Result<()> {
+        let oldest = self
+            .files
+            .values_mut()
+            .filter(|f| f.writer.is_some())
+            .min_by_key(|f| f.last_used);
+
+        if let Some(file) = oldest {
+            let writer = file.writer.take().expect("File should be open");
+            file.written = writer.position();
+            writer.finish()?;
+            self.open -= 1;
+        }
+        Ok(())
+    }
+
+    /// Writes a single record to the file of its barcode, opening the file if needed.
+    pub fn write(&mut self, rec: &Record, id: &RecordIdentifier) -> Result<()> {
+        self.clock += 1;
+
+        if !self.files.contains_key(&id.head) {
+            let name = self.file_name(&id.head);
+            self.files.insert(
+                id.head.clone(),
+                SplitFile {
+                    name,
+                    reads: 0,
+                    written: 0,
+                    last_used: 0,
+                    writer: None,
+                },
+            );
+        }
+
+        let is_open = self.files[&id.head].writer.is_some();
+        if !is_open && self.open >= self.max_open {
+            self.close_oldest()?;
+        }
+
+        let file = self.files.get_mut(&id.head).expect("File should exist");
+        if file.writer.is_none() {
+            let path = self.dir.join(&file.name);
+
+            // files are created when their first read is written, and appended to afterwards
+            let writer = if file.reads == 0 {
+                let out = File::create(&path)
+                    .with_context(|| format!("Could not create {}", path.display()))?;
+                RecordWriter::new(BufWriter::new(out), self.format, self.compression)?
+            } else {
+                let out = OpenOptions::new()
+                    .append(true)
+                    .open(&path)
+                    .with_context(|| format!("Could not reopen {}", path.display()))?;
+                RecordWriter::append(
+                    BufWriter::new(out),
+                    self.format,
+                    self.compression,
+                    file.written,
+                )?
+            };
+            file.writer = Some(writer);
+            self.open += 1;
+        }
+
+        file.last_used = self.clock;
+        file.reads += 1;
+        file.writer
+            .as_mut()
+            .expect("File should be open")
+            .write(rec, id)
+    }
+
+    /// Returns the total number of bytes written to every file so far, before any compression.
+    pub fn position(&self) -> u64 {
+        self.files
+            .values()
+            .map(|f| f.writer.as_ref().map_or(f.written, |w| w.position()))
+            .sum()
+    }
+
+    /// Flushes every open file.
+    pub fn flush(&mut self) -> Result<()> {
+        for writer in self.files.values_mut().filter_map(|f| f.writer.as_mut()) {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Closes every file, and writes the manifest.
+    pub fn finish(mut self) -> Result<()> {
+        for file in self.files.values_mut() {
+            if let Some(writer) = file.writer.take() {
+                writer.finish()?;
+            }
+        }
+
+        let path = self.dir.join("manifest.tsv");
+        let mut manifest = WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_path(&path)
+            .with_context(|| format!("Could not create manifest {}", path.display()))?;
+        for (barcode, file) in self.files.iter() {
+            manifest.serialize(ManifestRow {
+                barcode,
+                file: &file.name,
+                reads: file.reads,
+            })?;
+        }
+        manifest.flush()?;
+
+        info!("Wrote {} files to {}", self.files.len(), self.dir.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_name_collisions() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = SplitWriter::new(
+            dir.path().to_str().unwrap(),
+            OutputFormat::Fastq,
+            OutputCompression::Gzip,
+            1,
+        )
+        .unwrap();
+
+        assert_eq!(writer.file_name("AC:GT"), "AC_GT.fastq.gz");
+        assert_eq!(writer.file_name("AC.GT"), "AC_GT_2.fastq.gz");
+        assert_eq!(writer.file_name("ac_gt"), "ac_gt_3.fastq.gz");
+        assert_eq!(writer.file_name(""), "unknown.fastq.gz");
+    }
+}
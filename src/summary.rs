@@ -1,41 +1,399 @@
-use crate::{duplicates, index};
-use anyhow::{Context, Result};
-use serde_json::json;
+use crate::cells::{call_cells, write_called_barcodes, write_cell_statistics, CellCalls};
+use crate::cli::{CellArgs, ReportArgs, VERSION};
+use crate::duplicates::DuplicateStatistics;
+use crate::file::ReadFileMetadata;
+use crate::index::{self, IndexRecord};
+use crate::multiqc::{sample_name, write_multiqc};
+use anyhow::{ensure, Context, Result};
+use csv::WriterBuilder;
+use handlebars::{handlebars_helper, Handlebars};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::io::Write;
 
 // encode the template HTML file at compile time as a string literal
 const TEMPLATE_HTML: &str = include_str!("summary_template.html");
+const COMPARISON_TEMPLATE_HTML: &str = include_str!("summary_comparison_template.html");
 
-/// Summarizes the index and writes the output in HTML format to a file.
+/// The version of the `SummaryOutput` schema. This is increased whenever a field is removed or
+/// changes meaning; new fields may be added without changing it.
+///
+/// Version 2 moved the reports into the `reports` list, so that the output has the same shape
+/// for any number of samples.
+const SCHEMA_VERSION: u32 = 2;
+
+/// The format which `summary` writes.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SummaryFormat {
+    /// an HTML report, with a plot of the group size distribution
+    Html,
+
+    /// every statistic as a JSON object, including the group size distribution
+    Json,
+
+    /// a tab-separated table with a header and a single row, without the group size distribution
+    Tsv,
+}
+
+/// The width of each bin of the read length distribution, in bases.
+const LENGTH_BIN_WIDTH: usize = 50;
+
+/// The distributions of read length and quality of an index.
+///
+/// # Fields
+///
+/// * `length` - The number of reads in each bin of `LENGTH_BIN_WIDTH` bases, keyed by the
+///   shortest length in the bin
+/// * `quality` - The number of reads of each mean PHRED quality, rounded down
+#[derive(Serialize)]
+pub struct ReadProfile {
+    pub length: BTreeMap<usize, usize>,
+    pub quality: BTreeMap<usize, usize>,
+}
+
+/// The output of `summary --format json`.
+///
+/// # Fields
+///
+/// * `schema_version` - The version of this schema
+/// * `reports` - The statistics of each index, in the order they were given
+#[derive(Serialize)]
+struct SummaryOutput<'a> {
+    schema_version: u32,
+    reports: &'a [SummaryReport],
+}
+
+/// The statistics of an index.
+///
+/// # Fields
+///
+/// * `sample` - The name of the sample
+/// * `metadata` - Information about the input file, recorded when it was indexed
+/// * `duplicates` - The number of duplicate reads, and the distribution of group sizes
+/// * `reads` - The distributions of read length and quality
+/// * `unique_molecules` - The number of groups of any size
+/// * `saturation` - The sequencing saturation, `1 - unique_molecules / total_reads`
+/// * `cell_count` - The number of distinct cell barcodes
+/// * `cells` - The barcodes which were called as cells
+/// * `extra_depth` - The amount of additional sequencing, relative to the current depth, for
+///   which `new_molecules` is estimated
+/// * `new_molecules` - The estimated number of new unique molecules found by `extra_depth`
+///   times more sequencing
+#[derive(Serialize)]
+pub struct SummaryReport {
+    pub sample: String,
+    pub metadata: ReadFileMetadata,
+    pub duplicates: DuplicateStatistics,
+    pub reads: ReadProfile,
+    pub unique_molecules: usize,
+    pub saturation: f64,
+    pub cell_count: usize,
+    pub cells: CellCalls,
+    pub extra_depth: f64,
+    pub new_molecules: f64,
+}
+
+/// A row of the table written by `summary --format tsv`, which flattens a `SummaryReport`.
+#[derive(Serialize)]
+struct SummaryRow<'a> {
+    schema_version: u32,
+    sample: &'a str,
+    file_path: &'a str,
+    nailpolish_version: &'a str,
+    index_date: &'a str,
+    elapsed: f64,
+    gb: f64,
+    read_count: usize,
+    matched_read_count: usize,
+    unmatched_read_count: usize,
+    filtered_reads: usize,
+    avg_qual: f64,
+    avg_len: f64,
+    total_reads: usize,
+    duplicate_reads: usize,
+    duplicate_ids: usize,
+    proportion_duplicate: f64,
+    unique_molecules: usize,
+    saturation: f64,
+    cell_count: usize,
+    called_cells: usize,
+    cell_threshold: usize,
+    fraction_reads_in_cells: f64,
+    extra_depth: f64,
+    new_molecules: f64,
+}
+
+impl<'a> From<&'a SummaryReport> for SummaryRow<'a> {
+    fn from(report: &'a SummaryReport) -> Self {
+        let metadata = &report.metadata;
+        let duplicates = &report.duplicates;
+        SummaryRow {
+            schema_version: SCHEMA_VERSION,
+            sample: &report.sample,
+            file_path: &metadata.file_path,
+            nailpolish_version: &metadata.nailpolish_version,
+            index_date: &metadata.index_date,
+            elapsed: metadata.elapsed,
+            gb: metadata.gb,
+            read_count: metadata.read_count,
+            matched_read_count: metadata.matched_read_count,
+            unmatched_read_count: metadata.unmatched_read_count,
+            filtered_reads: metadata.filtered_reads,
+            avg_qual: metadata.avg_qual,
+            avg_len: metadata.avg_len,
+            total_reads: duplicates.total_reads,
+            duplicate_reads: duplicates.duplicate_reads,
+            duplicate_ids: duplicates.duplicate_ids,
+            proportion_duplicate: duplicates.proportion_duplicate,
+            unique_molecules: report.unique_molecules,
+            saturation: report.saturation,
+            cell_count: report.cell_count,
+            called_cells: report.cells.called_cells,
+            cell_threshold: report.cells.threshold,
+            fraction_reads_in_cells: report.cells.fraction_reads_in_cells,
+            extra_depth: report.extra_depth,
+            new_molecules: report.new_molecules,
+        }
+    }
+}
+
+// serializes any value of the context as JSON, for use in scripts as `{{{json report.duplicates}}}`
+handlebars_helper!(json_helper: |value: Json| serde_json::to_string(value).unwrap_or_default());
+
+/// Builds the data context which HTML templates are rendered against, as documented in the
+/// readme. Every summary is included in full as `reports`, and the summary of a single index is
+/// also given as `report`, along with fields formatted for display at the top level.
+fn template_context(reports: &[SummaryReport]) -> Result<Value> {
+    let mut data = json!({
+        "version": VERSION,
+        "sample_count": reports.len(),
+        "reports": reports,
+        "samples": serde_json::to_string(reports)?,
+    });
+
+    if let [report] = reports {
+        let metadata =
+            serde_json::to_value(&report.metadata).context("Could not serialize info")?;
+        if let Value::Object(fields) = metadata {
+            for (key, value) in fields {
+                data[key] = value;
+            }
+        }
+        data["report"] = json!(report);
+
+        // round "gb" stat to 3dp
+        data["gb"] = json!(format!("{:.3}", report.metadata.gb));
+        data["stats"] = json!(serde_json::to_string(&report.duplicates)?);
+        data["cell_count"] = json!(report.cell_count);
+        data["called_cells"] = json!(report.cells.called_cells);
+        data["cell_threshold"] = json!(report.cells.threshold);
+        data["reads_in_cells"] = json!(format!(
+            "{:.1}%",
+            100.0 * report.cells.fraction_reads_in_cells
+        ));
+        data["barcode_ranks"] = json!(serde_json::to_string(&report.cells.barcode_ranks)?);
+        data["saturation"] = json!(format!("{:.3}", report.saturation));
+        data["extra_depth"] = json!(report.extra_depth);
+        data["new_molecules"] = json!(format!("{:.0}", report.new_molecules));
+    }
+    Ok(data)
+}
+
+/// Renders the HTML report of one or more summaries.
 ///
 /// # Arguments
 ///
-/// * `index` - A string slice that holds the path to the index file.
-/// * `output` - A string slice that holds the path to the output file.
+/// * `reports` - The summary of each sample.
+/// * `template` - If set, the path to a handlebars template to render instead of the built-in
+///   report, which compares every sample if there is more than one.
+/// * `writer` - Where the report is written.
+fn write_html(reports: &[SummaryReport], template: Option<&str>, writer: impl Write) -> Result<()> {
+    let template = match template {
+        Some(path) => std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read template {path}"))?,
+        None if reports.len() == 1 => TEMPLATE_HTML.into(),
+        None => COMPARISON_TEMPLATE_HTML.into(),
+    };
+
+    let mut reg = Handlebars::new();
+    reg.register_helper("json", Box::new(json_helper));
+    reg.render_template_to_write(&template, &template_context(reports)?, writer)
+        .context("Could not render summary template")?;
+    Ok(())
+}
+
+/// Computes the distributions of read length and mean read quality of an index, in a single
+/// pass. Reads which were filtered out during indexing are left out.
+fn read_profile(index: &mut index::IndexReader) -> Result<ReadProfile> {
+    let mut profile = ReadProfile {
+        length: BTreeMap::new(),
+        quality: BTreeMap::new(),
+    };
+
+    for read in index.index_records()? {
+        let record: IndexRecord = read?;
+        if record.ignored {
+            continue;
+        }
+        let length = record.n_bases / LENGTH_BIN_WIDTH * LENGTH_BIN_WIDTH;
+        *profile.length.entry(length).or_insert(0) += 1;
+        *profile.quality.entry(record.avg_qual as usize).or_insert(0) += 1;
+    }
+    Ok(profile)
+}
+
+/// Summarizes a single index.
+///
+/// # Arguments
+///
+/// * `path` - The path to the index file.
+/// * `sample` - The name of the sample, or `None` to name it after its input file.
+/// * `cells` - How cells are called, and where per-cell statistics are written.
+/// * `extra_depth` - The amount of additional sequencing, relative to the current depth, to
+///   estimate the number of new unique molecules for.
+fn summarize_index(
+    path: &str,
+    sample: Option<&str>,
+    cells: &CellArgs,
+    extra_depth: f64,
+) -> Result<SummaryReport> {
+    info!("Summarising index at {path}");
+    let mut index = index::IndexReader::from_path(path)?;
+    let (duplicates, statistics) = index.get_duplicates()?;
+
+    let cell_stats = duplicates.cell_statistics();
+    if let Some(path) = &cells.cell_stats {
+        write_cell_statistics(path, &cell_stats)?;
+        info!("Wrote statistics for {} cells to {path}", cell_stats.len());
+    }
+    drop(duplicates);
+
+    let calls = call_cells(
+        &cell_stats,
+        cells.cell_calling,
+        cells.expected_cells,
+        statistics.total_reads,
+    );
+    if let Some(path) = &cells.called_barcodes {
+        write_called_barcodes(path, &cell_stats, &calls)?;
+    }
+
+    let reads = read_profile(&mut index)?;
+
+    let saturation = statistics.saturation();
+    let new_molecules = statistics.extrapolate_unique(extra_depth);
+    info!("Sequencing saturation: {:.3}", saturation);
+    info!(
+        "Estimated new unique molecules with {extra_depth}x additional sequencing: {:.0}",
+        new_molecules
+    );
+
+    Ok(SummaryReport {
+        sample: sample.map_or_else(|| sample_name(&index.metadata.file_path), String::from),
+        metadata: index.metadata,
+        unique_molecules: statistics.unique_molecules(),
+        duplicates: statistics,
+        reads,
+        saturation,
+        cell_count: cell_stats.len(),
+        cells: calls,
+        extra_depth,
+        new_molecules,
+    })
+}
+
+/// Summarizes one or more indexes and writes the output in the given format. A single index is
+/// written as in earlier versions, while several indexes are written as a comparison of every
+/// sample.
+///
+/// # Arguments
+///
+/// * `indexes` - The paths to the index files.
+/// * `names` - The name of each sample, in the same order as `indexes`. If empty, each sample is
+///   named after its input file.
+/// * `writer` - Where the summary is written.
+/// * `report_args` - The format of the summary, and the template of an HTML report.
+/// * `cells` - How cells are called, and where per-cell statistics and called barcodes are
+///   written. These files can only be written for a single index.
+/// * `extra_depth` - The amount of additional sequencing, relative to the current depth, to
+///   estimate the number of new unique molecules for.
+/// * `multiqc` - If set, the prefix of the MultiQC custom content files to write.
 ///
 /// # Returns
 ///
 /// * `Result<()>` - Returns an `Ok(())` if successful, or an `anyhow::Error` if an error occurs.
-pub fn summarize(index: &str, output: &str) -> Result<()> {
-    info!("Summarising index at {index}");
-    let mut index = index::IndexReader::from_path(index)?;
-    let (_, statistics) = index.get_duplicates()?;
-    let gb = index.metadata.gb;
-
-    let mut data = serde_json::to_value(index.metadata).context("Could not serialize info")?;
-
-    println!("{}", serde_json::to_string(&statistics)?);
-    // round "gb" stat to 3dp
-    data["gb"] = json!(format!("{:.3}", gb));
-    data["stats"] = json!(serde_json::to_string(&statistics)?);
-
-    println!(
-        "{}",
-        serde_json::to_string_pretty(&data).context("Should be serialisable")?
+pub fn summarize(
+    indexes: &[String],
+    names: &[String],
+    mut writer: impl Write,
+    report_args: &ReportArgs,
+    cells: &CellArgs,
+    extra_depth: f64,
+    multiqc: &Option<String>,
+) -> Result<()> {
+    ensure!(
+        names.is_empty() || names.len() == indexes.len(),
+        "Expected one --sample-name for each of the {} indexes, but got {}",
+        indexes.len(),
+        names.len()
+    );
+    ensure!(
+        (cells.cell_stats.is_none() && cells.called_barcodes.is_none()) || indexes.len() == 1,
+        "--cell-stats and --called-barcodes can only be used with a single index"
     );
 
-    let file = std::fs::File::create(output)?;
-    let reg = handlebars::Handlebars::new();
-    reg.render_template_to_write(TEMPLATE_HTML, &data, file)?;
+    let reports = indexes
+        .iter()
+        .enumerate()
+        .map(|(i, path)| {
+            summarize_index(path, names.get(i).map(String::as_str), cells, extra_depth)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut seen = HashSet::new();
+    for report in &reports {
+        ensure!(
+            seen.insert(&report.sample),
+            "More than one sample is named {}; please name each sample with --sample-name",
+            report.sample
+        );
+    }
+
+    if let Some(prefix) = multiqc {
+        write_multiqc(prefix, &reports)?;
+    }
+
+    if report_args.dump_context {
+        serde_json::to_writer_pretty(&mut writer, &template_context(&reports)?)
+            .context("Could not write template context")?;
+        writeln!(writer)?;
+        return Ok(writer.flush()?);
+    }
+
+    match report_args.format {
+        SummaryFormat::Html => write_html(&reports, report_args.template.as_deref(), &mut writer)?,
+        SummaryFormat::Json => {
+            let output = SummaryOutput {
+                schema_version: SCHEMA_VERSION,
+                reports: &reports,
+            };
+            serde_json::to_writer_pretty(&mut writer, &output)
+                .context("Could not write summary")?;
+            writeln!(writer)?;
+        }
+        SummaryFormat::Tsv => {
+            let mut wtr = WriterBuilder::new()
+                .delimiter(b'\t')
+                .from_writer(&mut writer);
+            for report in &reports {
+                wtr.serialize(SummaryRow::from(report))
+                    .context("Could not write summary")?;
+            }
+            wtr.flush()?;
+        }
+    }
+    writer.flush()?;
 
     Ok(())
 }
@@ -0,0 +1,196 @@
+use std::fmt::Display;
+use std::fs::File;
+
+use anyhow::{Context, Result};
+use needletail::parse_fastx_reader;
+use serde::Serialize;
+
+use crate::align::find_pattern;
+use crate::io::Record;
+use crate::orient::{longest_run, reverse_complement, END_WINDOW, MIN_TAIL_LEN};
+
+/// The maximum proportion of an adapter which can be mismatched for it to be trimmed.
+const MAX_ADAPTER_ERROR: f64 = 0.2;
+
+/// Options which control how each read is trimmed before consensus calling.
+///
+/// # Fields
+///
+/// * `adapters` - Adapter and primer sequences to trim from either end of each read
+/// * `poly_tails` - Whether to trim polyA tails from the end and polyT tails from the start
+/// * `trim_originals` - Whether original reads are written trimmed, rather than untrimmed
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct TrimOpts {
+    pub adapters: Vec<String>,
+    pub poly_tails: bool,
+    pub trim_originals: bool,
+}
+
+impl TrimOpts {
+    /// Returns true if any trimming is enabled.
+    pub fn enabled(&self) -> bool {
+        !self.adapters.is_empty() || self.poly_tails
+    }
+}
+
+/// The region of a read which is kept after trimming, as 0-based, end-exclusive coordinates.
+/// This is displayed as `start-end`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TrimRegion {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Display for TrimRegion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+/// Reads adapter and primer sequences from a .fasta (or .fastq) file.
+///
+/// # Returns
+///
+/// The upper case sequence of every record in the file.
+pub fn read_adapters(path: &str) -> Result<Vec<String>> {
+    let file = File::open(path).with_context(|| format!("Could not open adapter file {path}"))?;
+    let mut reader =
+        parse_fastx_reader(file).with_context(|| format!("Could not read adapter file {path}"))?;
+
+    let mut adapters = Vec::new();
+    while let Some(rec) = reader.next() {
+        let rec = rec.with_context(|| format!("Invalid record in adapter file {path}"))?;
+        let seq = String::from_utf8(rec.seq().to_ascii_uppercase())
+            .context("Adapter sequence is not valid UTF-8")?;
+
+        if !seq.is_empty() {
+            adapters.push(seq);
+        }
+    }
+
+    Ok(adapters)
+}
+
+/// Finds the region of a read which is kept after trimming.
+///
+/// Each adapter, and its reverse complement, is searched for near both ends of the read, and
+/// the read is trimmed up to the innermost match at each end. If `opts.poly_tails` is set, the
+/// longest polyA run near the end and the longest polyT run near the start of the remaining
+/// region are then trimmed, along with anything beyond them.
+pub fn trim_region(seq: &[u8], opts: &TrimOpts) -> TrimRegion {
+    let mut start = 0;
+    let mut end = seq.len();
+
+    for adapter in opts.adapters.iter() {
+        for pattern in [adapter.clone(), reverse_complement(adapter)] {
+            let pattern = pattern.as_bytes();
+            let window = (END_WINDOW + pattern.len()).min(seq.len());
+            let max_errors = (pattern.len() as f64 * MAX_ADAPTER_ERROR) as usize;
+
+            // at the start of the read, trim up to the end of the match
+            let (distance, match_end) = find_pattern(pattern, &seq[..window]);
+            if distance <= max_errors {
+                start = start.max(match_end);
+            }
+
+            // at the end of the read, search the reversed sequences so that the end of the match
+            // in the reversed window is the start of the match in the read
+            let rev_pattern: Vec<u8> = pattern.iter().rev().copied().collect();
+            let rev_window: Vec<u8> = seq[seq.len() - window..].iter().rev().copied().collect();
+            let (distance, match_end) = find_pattern(&rev_pattern, &rev_window);
+            if distance <= max_errors {
+                end = end.min(seq.len() - match_end);
+            }
+        }
+    }
+
+    if opts.poly_tails && start < end {
+        let window = END_WINDOW.min(end - start);
+
+        let (run_start, run_len) = longest_run(&seq[end - window..end], b'A');
+        if run_len >= MIN_TAIL_LEN {
+            end = end - window + run_start;
+        }
+
+        let window = END_WINDOW.min(end - start);
+        let (run_start, run_len) = longest_run(&seq[start..start + window], b'T');
+        if run_len >= MIN_TAIL_LEN {
+            start += run_start + run_len;
+        }
+    }
+
+    // adapters at both ends may overlap in very short reads, leaving nothing
+    TrimRegion {
+        start: start.min(end),
+        end,
+    }
+}
+
+impl Record {
+    /// Returns a copy of this record containing only the bases within `region`.
+    pub fn trimmed(&self, region: &TrimRegion) -> Record {
+        Record {
+            id: self.id.clone(),
+            seq: self.seq[region.start..region.end].to_string(),
+            qual: self
+                .qual
+                .get(region.start..region.end)
+                .unwrap_or_default()
+                .to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADAPTER: &str = "CTACACGACGCTCTTCCGATCT";
+
+    fn opts(adapters: &[&str], poly_tails: bool) -> TrimOpts {
+        TrimOpts {
+            adapters: adapters.iter().map(|a| a.to_string()).collect(),
+            poly_tails,
+            trim_originals: false,
+        }
+    }
+
+    /// A read insert which is long enough that the ends of the read are searched separately.
+    fn insert() -> String {
+        "GATTACACGT".repeat(30)
+    }
+
+    #[test]
+    fn untrimmed() {
+        let seq = insert();
+        let region = trim_region(seq.as_bytes(), &opts(&[ADAPTER], true));
+        assert_eq!(region, TrimRegion { start: 0, end: 300 });
+    }
+
+    #[test]
+    fn adapters_at_both_ends() {
+        let seq = format!("{ADAPTER}{}{}", insert(), reverse_complement(ADAPTER));
+        let region = trim_region(seq.as_bytes(), &opts(&[ADAPTER], false));
+        assert_eq!(
+            region,
+            TrimRegion {
+                start: ADAPTER.len(),
+                end: ADAPTER.len() + 300
+            }
+        );
+        assert_eq!(region.to_string(), "22-322");
+    }
+
+    #[test]
+    fn poly_tails() {
+        let seq = format!("{}{}{}", "T".repeat(15), insert(), "A".repeat(20));
+        let region = trim_region(seq.as_bytes(), &opts(&[], true));
+        assert_eq!(
+            region,
+            TrimRegion {
+                start: 15,
+                end: 315
+            }
+        );
+    }
+}
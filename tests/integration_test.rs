@@ -1,9 +1,152 @@
+use assert_cmd::assert::Assert;
 use assert_cmd::Command;
+use assert_fs::fixture::ChildPath;
 use assert_fs::prelude::*;
+use assert_fs::TempDir;
+use flate2::read::MultiGzDecoder;
 use predicates::prelude::*;
+use std::io::Read;
 
 const SAMPLE_FASTQ: &str = "tests/data/scmixology2_sample.fastq";
 
+/// Runs nailpolish with the given arguments, and checks that it succeeds.
+fn run(args: &[&str]) -> Assert {
+    Command::cargo_bin("nailpolish")
+        .unwrap()
+        .args(args)
+        .assert()
+        .success()
+}
+
+/// Returns the path of a file in a temporary directory.
+fn path(child: &ChildPath) -> &str {
+    child.path().to_str().unwrap()
+}
+
+/// Writes a small FASTQ file of 3 barcodes with 4 UMIs each, in groups of 1 to 4 reads whose
+/// reads are interleaved through the file, and indexes it. Each read ends in a polyA tail, and
+/// every fifth read is reverse complemented, so starts with a polyT tail instead.
+fn small_sample(dir: &TempDir) -> Sample {
+    const BASES: &[u8] = b"ACGT";
+    let mut state = 12345u64;
+    let mut next_base = || {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        BASES[(state >> 62) as usize]
+    };
+
+    let mut groups = Vec::new();
+    for (b, barcode) in ["AAAACCCCGGGGTTTT", "ACGTACGTACGTACGT", "TTTTGGGGCCCCAAAA"]
+        .iter()
+        .enumerate()
+    {
+        for (u, umi) in [
+            "AACCGGTTAACC",
+            "CCAAGGTTCCAA",
+            "GGTTAACCGGTT",
+            "TTGGCCAATTGG",
+        ]
+        .iter()
+        .enumerate()
+        {
+            // reads are between 150 and 205 bases long, with their polyA tail
+            let g = b * 4 + u;
+            let template: Vec<u8> = (0..130 + 5 * g).map(|_| next_base()).collect();
+            groups.push((format!("{barcode}_{umi}"), template, g % 4 + 1));
+        }
+    }
+
+    let mut fastq = String::new();
+    let mut n = 0;
+    for round in 0..4 {
+        for (id, template, size) in &groups {
+            if round >= *size {
+                continue;
+            }
+
+            // vary each read a little, so that the consensus is not trivial
+            let mut seq = template.clone();
+            seq[(n * 7) % template.len()] = b'A';
+            seq.extend_from_slice(&[b'A'; 20]);
+            if n % 5 == 4 {
+                seq = seq
+                    .iter()
+                    .rev()
+                    .map(|&b| BASES[3 - BASES.iter().position(|&c| c == b).unwrap()])
+                    .collect();
+            }
+
+            let seq = String::from_utf8(seq).unwrap();
+            let qual = "I".repeat(seq.len());
+            fastq.push_str(&format!("@{id}#read{n}\n{seq}\n+\n{qual}\n"));
+            n += 1;
+        }
+    }
+
+    let input = dir.child("sample.fastq");
+    input.write_str(&fastq).unwrap();
+    let index = dir.child("index.tsv");
+    run(&["index", path(&input), "-o", path(&index)]);
+
+    Sample {
+        input: path(&input).into(),
+        index: path(&index).into(),
+    }
+}
+
+/// The paths of a FASTQ file written by `small_sample`, and of its index.
+struct Sample {
+    input: String,
+    index: String,
+}
+
+impl Sample {
+    /// Runs `call` or `group` on the sample with the given arguments, writing to `output`, and
+    /// checks that it succeeds.
+    fn run(&self, command: &str, output: &ChildPath, args: &[&str]) -> Assert {
+        let input = [
+            "--index",
+            &self.index,
+            "--input",
+            &self.input,
+            "-o",
+            path(output),
+        ];
+        run(&[&[command][..], &input, args].concat())
+    }
+}
+
+/// Returns the read names and tags, and the sequences, of the consensus reads of a FASTQ file.
+fn consensus_reads(path: &std::path::Path) -> Vec<(String, String)> {
+    read_headers(path)
+        .into_iter()
+        .zip(read_sequences(path))
+        .filter(|(h, _)| !h.contains("UT:Z:ORIG"))
+        .collect()
+}
+
+/// Returns the sequences of a FASTQ file.
+fn read_sequences(path: &std::path::Path) -> Vec<String> {
+    std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .step_by(4)
+        .map(String::from)
+        .collect()
+}
+
+/// Returns the read names and tags of a FASTQ file, one line per read.
+fn read_headers(path: &std::path::Path) -> Vec<String> {
+    std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .step_by(4)
+        .map(|l| l[1..].to_string())
+        .collect()
+}
+
 #[test]
 fn index() {
     let temp = assert_fs::NamedTempFile::new("_index.tsv").unwrap();
@@ -15,10 +158,15 @@ fn index() {
         .assert()
         .success();
 
+    temp.assert(predicate::str::contains(
+        "id\tpos\tavg_qual\tn_bases\trec_len\tignored\tname\n",
+    ));
+
     // lazy way of checking that these files are the same
-    // EXCEPT for the header, which contains unique date and runtime information
+    // EXCEPT for the header, which contains unique date and runtime information,
+    // and the read names, which the reference index predates
     let cmp_cmd = format!(
-        "diff <(tail -n+2 tests/correct/index.tsv) <(tail -n+2 {})",
+        "diff <(tail -n+2 tests/correct/index.tsv) <(tail -n+2 {} | cut -f1-6)",
         temp.path().to_str().unwrap()
     );
 
@@ -47,6 +195,274 @@ fn summary() {
     temp.assert(predicate::path::exists());
 }
 
+#[test]
+fn summary_cell_stats() {
+    let temp = assert_fs::NamedTempFile::new("_summary.html").unwrap();
+    let cells = assert_fs::NamedTempFile::new("_cells.tsv").unwrap();
+
+    let mut command = Command::cargo_bin("nailpolish").unwrap();
+
+    let _ = command
+        .args(&[
+            "summary",
+            "--index",
+            "tests/correct/index.tsv",
+            "-o",
+            temp.path().to_str().unwrap(),
+            "--cell-stats",
+            cells.path().to_str().unwrap(),
+        ])
+        .assert()
+        .success();
+
+    cells.assert(predicate::str::starts_with(
+        "barcode\treads\tumis\tduplicate_reads\tproportion_duplicate\tsaturation\n",
+    ));
+}
+
+#[test]
+fn summary_extra_depth() {
+    let args = [
+        "summary",
+        "--index",
+        "tests/correct/index.tsv",
+        "--format",
+        "json",
+    ];
+    run(&[&args[..], &["--extra-depth", "0.5"]].concat())
+        .stdout(predicate::str::contains("\"extra_depth\": 0.5"));
+
+    for depth in ["0", "-1", "nan", "inf"] {
+        Command::cargo_bin("nailpolish")
+            .unwrap()
+            .args(args)
+            .arg(format!("--extra-depth={depth}"))
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("greater than 0"));
+    }
+}
+
+#[test]
+fn summary_json() {
+    let mut command = Command::cargo_bin("nailpolish").unwrap();
+
+    // only the summary is written to stdout
+    let _ = command
+        .args(&[
+            "summary",
+            "--index",
+            "tests/correct/index.tsv",
+            "--format",
+            "json",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with(
+            "{\n  \"schema_version\": 2,\n  \"reports\": [",
+        ))
+        .stdout(predicate::str::contains("\"distribution\""));
+}
+
+#[test]
+fn summary_tsv() {
+    let temp = assert_fs::NamedTempFile::new("_summary.tsv").unwrap();
+
+    let mut command = Command::cargo_bin("nailpolish").unwrap();
+
+    let _ = command
+        .args(&[
+            "summary",
+            "--index",
+            "tests/correct/index.tsv",
+            "--format",
+            "tsv",
+            "-o",
+            temp.path().to_str().unwrap(),
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::is_empty());
+
+    temp.assert(predicate::str::starts_with(
+        "schema_version\tsample\tfile_path\t",
+    ));
+}
+
+#[test]
+fn summary_multiqc() {
+    let dir = assert_fs::TempDir::new().unwrap();
+    let temp = assert_fs::NamedTempFile::new("_summary.html").unwrap();
+    let prefix = dir.child("sample");
+
+    let mut command = Command::cargo_bin("nailpolish").unwrap();
+
+    let _ = command
+        .args(&[
+            "summary",
+            "--index",
+            "tests/correct/index.tsv",
+            "-o",
+            temp.path().to_str().unwrap(),
+            "--multiqc",
+            prefix.path().to_str().unwrap(),
+            "--sample-name",
+            "sample1",
+        ])
+        .assert()
+        .success();
+
+    dir.child("sample.general_stats_mqc.json")
+        .assert(predicate::str::contains("\"sample1\": {"));
+    dir.child("sample.group_sizes_mqc.json")
+        .assert(predicate::str::contains("\"plot_type\": \"bargraph\""));
+}
+
+#[test]
+fn summary_comparison() {
+    let temp = assert_fs::NamedTempFile::new("_comparison.html").unwrap();
+    let table = assert_fs::NamedTempFile::new("_comparison.tsv").unwrap();
+
+    for (format, output) in [("html", &temp), ("tsv", &table)] {
+        let mut command = Command::cargo_bin("nailpolish").unwrap();
+        let _ = command
+            .args(&[
+                "summary",
+                "--index",
+                "tests/correct/index.tsv",
+                "tests/correct/index.tsv",
+                "--sample-name",
+                "first",
+                "second",
+                "--format",
+                format,
+                "-o",
+                output.path().to_str().unwrap(),
+            ])
+            .assert()
+            .success();
+    }
+
+    temp.assert(predicate::str::contains("nailpolish sample comparison"));
+    table.assert(
+        predicate::str::contains("\n2\tfirst\t").and(predicate::str::contains("\n2\tsecond\t")),
+    );
+
+    // samples must be told apart
+    let mut command = Command::cargo_bin("nailpolish").unwrap();
+    let _ = command
+        .args(&[
+            "summary",
+            "--index",
+            "tests/correct/index.tsv",
+            "tests/correct/index.tsv",
+            "--format",
+            "json",
+        ])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn summary_called_barcodes() {
+    let temp = assert_fs::NamedTempFile::new("_summary.html").unwrap();
+    let called = assert_fs::NamedTempFile::new("_cells.txt").unwrap();
+
+    let mut command = Command::cargo_bin("nailpolish").unwrap();
+
+    let _ = command
+        .args(&[
+            "summary",
+            "--index",
+            "tests/correct/index.tsv",
+            "-o",
+            temp.path().to_str().unwrap(),
+            "--expected-cells",
+            "1",
+            "--called-barcodes",
+            called.path().to_str().unwrap(),
+        ])
+        .assert()
+        .success();
+
+    temp.assert(predicate::str::contains("Barcode rank"));
+    called.assert(predicate::str::is_match("^[ACGTN]+\n").unwrap());
+}
+
+#[test]
+fn summary_expected_cells() {
+    Command::cargo_bin("nailpolish")
+        .unwrap()
+        .args(["summary", "--index", "tests/correct/index.tsv"])
+        .args(["--expected-cells", "0"])
+        .assert()
+        .failure();
+
+    // an index with no grouped reads has no reads in cells
+    let dir = TempDir::new().unwrap();
+    let empty = dir.child("index.tsv");
+    let header: String = std::fs::read_to_string("tests/correct/index.tsv")
+        .unwrap()
+        .lines()
+        .take(2)
+        .map(|l| format!("{l}\n"))
+        .collect();
+    empty.write_str(&header).unwrap();
+
+    run(&["summary", "--index", path(&empty), "--format", "json"])
+        .stdout(predicate::str::contains("\"fraction_reads_in_cells\": 0.0"));
+}
+
+#[test]
+fn summary_template() {
+    let template = assert_fs::NamedTempFile::new("template.hbs").unwrap();
+    let temp = assert_fs::NamedTempFile::new("_summary.html").unwrap();
+    template
+        .write_str("{{report.sample}}: {{{json report.duplicates.total_reads}}} reads")
+        .unwrap();
+
+    let mut command = Command::cargo_bin("nailpolish").unwrap();
+    let _ = command
+        .args(&[
+            "summary",
+            "--index",
+            "tests/correct/index.tsv",
+            "--sample-name",
+            "sample1",
+            "--template",
+            template.path().to_str().unwrap(),
+            "-o",
+            temp.path().to_str().unwrap(),
+        ])
+        .assert()
+        .success();
+
+    temp.assert(predicate::str::is_match("^sample1: [0-9]+ reads$").unwrap());
+
+    let mut command = Command::cargo_bin("nailpolish").unwrap();
+    let _ = command
+        .args(&[
+            "summary",
+            "--index",
+            "tests/correct/index.tsv",
+            "--dump-context",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\"reports\": ["))
+        .stdout(predicate::str::contains("\"distribution\": {"));
+
+    // templates are only rendered for the html format
+    Command::cargo_bin("nailpolish")
+        .unwrap()
+        .args(["summary", "--index", "tests/correct/index.tsv"])
+        .args(["--template", template.path().to_str().unwrap()])
+        .args(["--format", "json"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot be used with"));
+}
+
 #[test]
 fn consensus_1t() {
     let temp = assert_fs::NamedTempFile::new("consensus.fastq").unwrap();
@@ -104,3 +520,601 @@ fn consensus_4t() {
 
     temp.close().unwrap();
 }
+
+#[test]
+fn group_external() {
+    let temp_mem = assert_fs::NamedTempFile::new("group_mem.fastq").unwrap();
+    let temp_ext = assert_fs::NamedTempFile::new("group_ext.fastq").unwrap();
+    let tmp_dir = assert_fs::TempDir::new().unwrap();
+
+    let mut command = Command::cargo_bin("nailpolish").unwrap();
+    let _ = command
+        .args(&[
+            "group",
+            "--index",
+            "tests/correct/index.tsv",
+            "--input",
+            SAMPLE_FASTQ,
+            "-o",
+            temp_mem.path().to_str().unwrap(),
+        ])
+        .assert()
+        .success();
+
+    // use the smallest memory limit, so that the runs are written to and read back from disk.
+    // merging several runs is tested in external.rs, as this sample may fit in a single run
+    let mut command = Command::cargo_bin("nailpolish").unwrap();
+    let _ = command
+        .args(&[
+            "group",
+            "--index",
+            "tests/correct/index.tsv",
+            "--input",
+            SAMPLE_FASTQ,
+            "-o",
+            temp_ext.path().to_str().unwrap(),
+            "--tmp-dir",
+            tmp_dir.path().to_str().unwrap(),
+            "--memory-limit",
+            "1",
+        ])
+        .assert()
+        .success();
+
+    let cmp_cmd = format!(
+        "diff {} {}",
+        temp_mem.path().to_str().unwrap(),
+        temp_ext.path().to_str().unwrap()
+    );
+
+    let _ = Command::new("bash").arg("-c").arg(&cmp_cmd).unwrap();
+
+    temp_mem.close().unwrap();
+    temp_ext.close().unwrap();
+}
+
+#[test]
+fn assign_matches_group() {
+    let dir = TempDir::new().unwrap();
+    let sample = small_sample(&dir);
+    let grouped = dir.child("grouped.fastq");
+    let assigned = dir.child("assigned.tsv");
+
+    for selection in [&[][..], &["--select-min-size", "2"][..]] {
+        sample.run("group", &grouped, selection);
+        let args = ["assign", "--index", &sample.index, "-o", path(&assigned)];
+        run(&[&args[..], selection].concat());
+
+        // the group of each read, from the `UG:i` tag of `group`
+        let mut from_group: Vec<(String, String)> = std::fs::read_to_string(grouped.path())
+            .unwrap()
+            .lines()
+            .filter(|l| l.starts_with('@'))
+            .map(|l| {
+                let mut fields = l[1..].split(' ');
+                let name = fields.next().unwrap().to_string();
+                let group = fields.find_map(|f| f.strip_prefix("UG:i:")).unwrap();
+                (name, group.to_string())
+            })
+            .collect();
+
+        // the group of each read, from the `group` column of `assign`
+        let mut from_assign: Vec<(String, String)> = std::fs::read_to_string(assigned.path())
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|l| {
+                let fields: Vec<&str> = l.split('\t').collect();
+                (fields[0].to_string(), fields[3].to_string())
+            })
+            .collect();
+
+        from_group.sort();
+        from_assign.sort();
+        assert!(!from_assign.is_empty());
+        assert_eq!(from_group, from_assign);
+    }
+
+    // an index without read names is refused before anything is written
+    let old_index = dir.child("old_index.tsv");
+    let without_names: String = std::fs::read_to_string(&sample.index)
+        .unwrap()
+        .lines()
+        .map(|l| match l.rsplit_once('\t') {
+            Some((rest, _)) if !l.starts_with('#') => format!("{rest}\n"),
+            _ => format!("{l}\n"),
+        })
+        .collect();
+    old_index.write_str(&without_names).unwrap();
+
+    Command::cargo_bin("nailpolish")
+        .unwrap()
+        .args([
+            "assign",
+            "--index",
+            path(&old_index),
+            "--select-min-size",
+            "5",
+        ])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("does not record read names"));
+}
+
+#[test]
+fn call_max_group_size() {
+    let dir = TempDir::new().unwrap();
+    let sample = small_sample(&dir);
+    let output = dir.child("downsampled.fastq");
+
+    for strategy in ["quality", "length", "random"] {
+        let args = ["--max-group-size", "2", "--downsample-by", strategy];
+        sample.run("call", &output, &args);
+
+        // groups are called from fewer reads, but still report their full size
+        let headers = read_headers(output.path());
+        assert_eq!(headers.len(), 12);
+        assert!(headers.iter().any(|h| h.contains("UT:Z:CON_4")));
+    }
+}
+
+#[test]
+fn call_alignment_params() {
+    let dir = TempDir::new().unwrap();
+    let sample = small_sample(&dir);
+    let output = dir.child("aligned.fastq");
+
+    let args = ["--poa-profile", "hifi", "--alignment", "global"];
+    let scores = ["--match-score", "3", "--mismatch-score=-5", "--gap-open=-6"];
+    sample
+        .run("call", &output, &[&args[..], &scores].concat())
+        .stderr(predicate::str::contains("--gap-open-2"));
+    assert_eq!(read_headers(output.path()).len(), 12);
+
+    // the parameters used are recorded next to the output
+    let params = dir.child("aligned.fastq.params.json");
+    params.assert(predicate::str::contains("\"match_score\": 3"));
+    params.assert(predicate::str::contains("\"gap_open_2\": -24"));
+
+    let gaps = ["--gap-open=-6", "--gap-open-2=-30", "--gap-extend-2=-1"];
+    sample
+        .run("call", &output, &gaps)
+        .stderr(predicate::str::contains("--gap-open-2").not());
+    params.assert(predicate::str::contains("\"gap_open_2\": -30"));
+    params.assert(predicate::str::contains("\"gap_extend_2\": -1"));
+}
+
+#[test]
+fn call_representative() {
+    let dir = TempDir::new().unwrap();
+    let sample = small_sample(&dir);
+    let output = dir.child("representative.fastq");
+    let inputs = read_sequences(std::path::Path::new(&sample.input));
+
+    for by in ["quality", "length", "medoid"] {
+        let args = ["--mode", "representative", "--representative-by", by];
+        sample.run("call", &output, &args);
+
+        // every output read is one of the input reads
+        let sequences = read_sequences(output.path());
+        assert_eq!(sequences.len(), 12);
+        assert!(sequences.iter().all(|s| inputs.contains(s)));
+    }
+    // in a group too large to compare every pair of reads, the medoid is still the read with
+    // the fewest differences. each read has its own differences from the first read
+    let template: Vec<u8> = (0..500).map(|i| b"ACGT"[(i * i + i / 3) % 4]).collect();
+    let mut fastq = String::new();
+    for k in 0..40 {
+        let mut seq = template.clone();
+        if k > 0 {
+            for m in 0..k % 10 + 1 {
+                let pos = k * 12 + m;
+                seq[pos] = if seq[pos] == b'A' { b'C' } else { b'A' };
+            }
+        }
+        let seq = String::from_utf8(seq).unwrap();
+        let qual = "I".repeat(seq.len());
+        fastq.push_str(&format!(
+            "@AAAACCCCGGGGTTTT_AACCGGTTAACC#read{k}\n{seq}\n+\n{qual}\n"
+        ));
+    }
+
+    let large = Sample {
+        input: path(&dir.child("large.fastq")).into(),
+        index: path(&dir.child("large.tsv")).into(),
+    };
+    dir.child("large.fastq").write_str(&fastq).unwrap();
+    run(&["index", &large.input, "-o", &large.index]);
+
+    for seed in ["0", "1", "2"] {
+        let args = ["--mode", "representative", "--representative-by", "medoid"];
+        large.run("call", &output, &[&args[..], &["--seed", seed]].concat());
+        assert_eq!(
+            read_sequences(output.path()),
+            [String::from_utf8(template.clone()).unwrap()]
+        );
+    }
+}
+
+#[test]
+fn call_orient() {
+    let dir = TempDir::new().unwrap();
+    let sample = small_sample(&dir);
+    let output = dir.child("oriented.fastq");
+
+    for method in ["polya", "align"] {
+        sample.run("call", &output, &["--orient", method, "-r"]);
+
+        // consensus reads are on the common strand, and some original reads are not
+        let consensus = consensus_reads(output.path());
+        assert_eq!(consensus.len(), 12);
+        assert!(consensus.iter().all(|(h, _)| h.ends_with("ST:A:+")));
+        let headers = read_headers(output.path());
+        assert!(headers.iter().any(|h| h.ends_with("ST:A:-")));
+
+        // with polyA tails, every consensus read ends in the tail
+        if method == "polya" {
+            assert!(consensus.iter().all(|(_, s)| s.ends_with(&"A".repeat(15))));
+        }
+    }
+}
+
+#[test]
+fn call_trim() {
+    let dir = TempDir::new().unwrap();
+    let sample = small_sample(&dir);
+    let output = dir.child("trimmed.fastq");
+
+    sample.run(
+        "call",
+        &output,
+        &["--trim-poly-tails", "--orient", "polya", "-r"],
+    );
+
+    // the kept region is recorded for every original read, and singletons are their own
+    let headers = read_headers(output.path());
+    let originals = headers
+        .iter()
+        .filter(|h| h.contains("UT:Z:ORIG") || h.contains("UT:Z:SIN"));
+    assert!(originals.clone().count() > 12);
+    assert!(originals.clone().all(|h| h.contains("TR:Z:")));
+
+    let consensus = consensus_reads(output.path());
+    assert!(consensus.iter().all(|(_, s)| !s.ends_with("AAAAAAAAAA")));
+}
+
+#[test]
+fn call_ignored() {
+    let dir = TempDir::new().unwrap();
+    let sample = small_sample(&dir);
+    let output = dir.child("called.fastq");
+    let ignored = dir.child("ignored.fastq");
+
+    // filter out the longer reads
+    let filtered = Sample {
+        input: sample.input.clone(),
+        index: path(&dir.child("filtered.tsv")).into(),
+    };
+    run(&[
+        "index",
+        &sample.input,
+        "--len",
+        "0,180",
+        "-o",
+        &filtered.index,
+    ]);
+
+    filtered.run("call", &output, &["--include-ignored"]);
+    let included = read_headers(output.path());
+    let ignored_count = included.iter().filter(|h| h.contains("UT:Z:IGN")).count();
+    assert!(ignored_count > 0);
+
+    filtered.run("call", &output, &["--ignored-output", path(&ignored)]);
+    assert_eq!(read_headers(ignored.path()).len(), ignored_count);
+    assert_eq!(
+        read_headers(output.path()).len(),
+        included.len() - ignored_count
+    );
+}
+
+#[test]
+fn call_output_formats() {
+    let dir = TempDir::new().unwrap();
+    let sample = small_sample(&dir);
+
+    for (format, start) in [
+        ("fasta", &b">"[..]),
+        ("sam", &b"@HD\tVN:1.6"[..]),
+        ("ubam", &b"BAM\x01"[..]),
+    ] {
+        let output = dir.child(format!("called.{format}"));
+        sample.run("call", &output, &["--output-format", format]);
+
+        let mut contents = std::fs::read(output.path()).unwrap();
+        if format == "ubam" {
+            let mut decompressed = Vec::new();
+            MultiGzDecoder::new(&contents[..])
+                .read_to_end(&mut decompressed)
+                .unwrap();
+            contents = decompressed;
+        }
+        assert!(contents.starts_with(start), "{format} output is malformed");
+    }
+}
+
+#[test]
+fn call_compressed() {
+    let dir = TempDir::new().unwrap();
+    let sample = small_sample(&dir);
+    let plain = dir.child("called.fastq");
+
+    sample.run("call", &plain, &[]);
+    let expected = std::fs::read(plain.path()).unwrap();
+
+    for name in ["called.fastq.gz", "called.fastq.bgz"] {
+        let output = dir.child(name);
+        sample.run("call", &output, &["-t", "4"]);
+
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(std::fs::File::open(output.path()).unwrap())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, expected, "{name} differs");
+    }
+}
+
+#[test]
+fn call_qc() {
+    let dir = TempDir::new().unwrap();
+    let sample = small_sample(&dir);
+    let output = dir.child("called.fastq");
+    let qc = dir.child("qc.tsv");
+
+    sample.run("call", &output, &["--qc", path(&qc)]);
+
+    // a header, and a row per group
+    let rows = std::fs::read_to_string(qc.path()).unwrap();
+    assert_eq!(rows.lines().count(), 13);
+    assert!(rows.starts_with("group\tid\tgroup_size\t"));
+}
+
+#[test]
+fn call_msa() {
+    let dir = TempDir::new().unwrap();
+    let sample = small_sample(&dir);
+    let output = dir.child("called.fastq");
+    let msa = dir.child("msa");
+
+    let args = [
+        "--msa-groups",
+        "0,1",
+        "--msa-ids",
+        "ACGTACGTACGTACGT_CCAAGGTTCCAA",
+    ];
+    sample.run(
+        "call",
+        &output,
+        &[&["--msa-dir", path(&msa)][..], &args].concat(),
+    );
+
+    msa.child("group_0.fasta").assert(predicate::path::exists());
+    msa.child("group_1.fasta").assert(predicate::path::exists());
+    msa.child("group_5.fasta")
+        .assert(predicate::str::starts_with(">"));
+    msa.child("group_2.fasta")
+        .assert(predicate::path::missing());
+}
+
+#[test]
+fn call_resume() {
+    let dir = TempDir::new().unwrap();
+    let sample = small_sample(&dir);
+    let expected = dir.child("expected.fastq");
+    let output = dir.child("resumed.fastq");
+    let checkpoint = dir.child("resumed.fastq.checkpoint");
+
+    // checkpoints are only saved when asked for, and are removed once the run finishes
+    sample.run("call", &expected, &[]);
+    dir.child("expected.fastq.checkpoint")
+        .assert(predicate::path::missing());
+    sample.run("call", &output, &["--checkpoint"]);
+    checkpoint.assert(predicate::path::missing());
+
+    // without a checkpoint, the run starts from the beginning
+    output.write_str("partial output").unwrap();
+    sample.run("call", &output, &["--resume"]);
+    assert_eq!(
+        std::fs::read(output.path()).unwrap(),
+        std::fs::read(expected.path()).unwrap()
+    );
+    checkpoint.assert(predicate::path::missing());
+
+    // a checkpoint of another run is refused
+    checkpoint
+        .write_str(
+            r#"{"fingerprint":{"input":"other.fastq","input_size":1,
+            "input_modified":{"secs_since_epoch":0,"nanos_since_epoch":0},
+            "index":"other.tsv","opts":{}},"groups":1,"offset":10}"#,
+        )
+        .unwrap();
+    Command::cargo_bin("nailpolish")
+        .unwrap()
+        .args(["call", "--index", &sample.index, "--input", &sample.input])
+        .args(["-o", path(&output), "--resume"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "different input, index or options",
+        ));
+
+    // checkpoints cannot be saved for compressed output
+    Command::cargo_bin("nailpolish")
+        .unwrap()
+        .args(["call", "--index", &sample.index, "--input", &sample.input])
+        .args(["-o", path(&dir.child("called.fastq.gz")), "--checkpoint"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn call_selection() {
+    let dir = TempDir::new().unwrap();
+    let sample = small_sample(&dir);
+    let barcodes = dir.child("barcodes.txt");
+    barcodes.write_str("ACGTACGTACGTACGT\n").unwrap();
+    let all = dir.child("all.fastq");
+    let selected = dir.child("selected.fastq");
+
+    sample.run("call", &all, &[]);
+    let all = read_headers(all.path());
+
+    // selected groups keep the `UG:i` tag which they have when every group is called, however
+    // duplicates are grouped and read
+    let tmp_dir = dir.path().to_str().unwrap();
+    let check = |args: &[&str], n: usize, selects: &dyn Fn(&str) -> bool| {
+        for grouping in [
+            &[][..],
+            &["--tmp-dir", tmp_dir][..],
+            &["--read-by", "partition", "--tmp-dir", tmp_dir][..],
+        ] {
+            sample.run("call", &selected, &[args, grouping].concat());
+            let headers = read_headers(selected.path());
+            assert_eq!(headers.len(), n);
+            assert!(headers.iter().all(|h| selects(h)));
+            assert!(headers.iter().all(|h| all.contains(h)));
+        }
+    };
+
+    check(&["--barcodes", path(&barcodes)], 4, &|h| {
+        h.starts_with("ACGTACGTACGTACGT_")
+    });
+    check(
+        &["--select-min-size", "2", "--select-max-size", "3"],
+        6,
+        &|h| h.contains("UT:Z:CON_2") || h.contains("UT:Z:CON_3"),
+    );
+    check(
+        &["--barcodes", path(&barcodes), "--select-min-size", "4"],
+        1,
+        &|h| h.starts_with("ACGTACGTACGTACGT_") && h.contains("UT:Z:CON_4"),
+    );
+}
+
+#[test]
+fn call_split() {
+    let dir = TempDir::new().unwrap();
+    let sample = small_sample(&dir);
+    let output = dir.child("split");
+
+    let args = ["--split-by", "barcode", "--max-open-files", "1"];
+    sample.run("call", &output, &args);
+
+    for barcode in ["AAAACCCCGGGGTTTT", "ACGTACGTACGTACGT", "TTTTGGGGCCCCAAAA"] {
+        let file = output.child(format!("{barcode}.fastq"));
+        let headers = read_headers(file.path());
+        assert_eq!(headers.len(), 4);
+        assert!(headers.iter().all(|h| h.starts_with(barcode)));
+    }
+    output
+        .child("manifest.tsv")
+        .assert(predicate::str::contains(
+            "ACGTACGTACGTACGT\tACGTACGTACGTACGT.fastq\t4",
+        ));
+}
+
+#[test]
+fn group_command() {
+    let dir = TempDir::new().unwrap();
+    let sample = small_sample(&dir);
+    let grouped = dir.child("grouped.fastq");
+    let piped = dir.child("piped.fastq");
+
+    sample.run("group", &grouped, &[]);
+    let grouped = std::fs::read_to_string(grouped.path()).unwrap();
+    let lines = |path: &ChildPath| -> Vec<String> {
+        let output = std::fs::read_to_string(path.path()).unwrap();
+        output.lines().map(String::from).collect()
+    };
+
+    // each group is passed through the command in turn, in group order, and a single argument
+    // is run as a command line
+    for command in ["cat", "cat | cat"] {
+        sample.run("group", &piped, &["-t", "4", command]);
+        assert_eq!(lines(&piped), grouped.lines().collect::<Vec<_>>());
+    }
+
+    // several arguments are passed to the command as given
+    sample.run("group", &piped, &["awk", "{print $1}"]);
+    let first_fields: Vec<&str> = grouped
+        .lines()
+        .map(|l| l.split(' ').next().unwrap())
+        .collect();
+    assert_eq!(lines(&piped), first_fields);
+
+    Command::cargo_bin("nailpolish")
+        .unwrap()
+        .args(["group", "--index", &sample.index, "--input", &sample.input])
+        .arg("false")
+        .assert()
+        .failure();
+}
+
+#[test]
+fn group_partitioned() {
+    let dir = TempDir::new().unwrap();
+    let sample = small_sample(&dir);
+    let expected = dir.child("expected.fastq");
+    let output = dir.child("grouped.fastq");
+    let tmp_dir = dir.child("tmp");
+    std::fs::create_dir(tmp_dir.path()).unwrap();
+
+    sample.run("group", &expected, &[]);
+    let args = ["--read-by", "partition", "--partitions", "3"];
+    sample.run(
+        "group",
+        &output,
+        &[&args[..], &["--tmp-dir", path(&tmp_dir)]].concat(),
+    );
+
+    // partitioned reads give the same reads and groups, but in a different order
+    let records = |path: &ChildPath| {
+        let fastq = std::fs::read_to_string(path.path()).unwrap();
+        let lines: Vec<&str> = fastq.lines().collect();
+        let mut records: Vec<String> = lines.chunks(4).map(|r| r.join("\n")).collect();
+        records.sort();
+        records
+    };
+    assert_eq!(records(&output), records(&expected));
+}
+
+#[test]
+fn group_batched_reads() {
+    let dir = TempDir::new().unwrap();
+    let sample = small_sample(&dir);
+    let expected = dir.child("expected.fastq");
+    let output = dir.child("grouped.fastq");
+
+    // reading coalesced batches of records in parallel gives the same output
+    sample.run("group", &expected, &[]);
+    sample.run("group", &output, &["--io-threads", "4"]);
+    assert_eq!(
+        std::fs::read_to_string(output.path()).unwrap(),
+        std::fs::read_to_string(expected.path()).unwrap()
+    );
+}
+
+#[test]
+fn group_mmap() {
+    let dir = TempDir::new().unwrap();
+    let sample = small_sample(&dir);
+    let expected = dir.child("expected.fastq");
+    let output = dir.child("grouped.fastq");
+
+    // memory mapped reads give the same output, with or without parallel batches
+    sample.run("group", &expected, &[]);
+    let expected = std::fs::read_to_string(expected.path()).unwrap();
+    for args in [&["--mmap"][..], &["--io-threads", "2", "--mmap"][..]] {
+        sample.run("group", &output, args);
+        assert_eq!(std::fs::read_to_string(output.path()).unwrap(), expected);
+    }
+}